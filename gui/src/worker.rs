@@ -4,7 +4,9 @@ use futures_channel::mpsc;
 use pkgar::{ext::EntryExt, PackageHead};
 use pkgar_core::PackageSrc;
 use pkgar_keys::PublicKeyFile;
-use redox_installer::{try_fast_install, with_redoxfs_mount, with_whole_disk, Config, DiskOption};
+use redox_installer::{
+    try_fast_install, with_redoxfs_mount, with_whole_disk, Config, DiskOption, FilesystemSize,
+};
 use std::{
     ffi::OsStr,
     fs::{self, File},
@@ -216,7 +218,7 @@ pub(crate) fn install<F: FnMut(Message)>(target: TargetConfig, profile: InstallC
     config.general.live_disk = Some(profile.live_disk);
     config.general.encrypt_disk = password_opt;
     config.general.skip_partitions = Some(target.is_skip_partition());
-    config.general.filesystem_size = Some(target.install_size_mb() as u32);
+    config.general.filesystem_size = Some(FilesystemSize::Mb(target.install_size_mb() as u32));
 
     macro_rules! message {
         ($($arg:tt)*) => {{
@@ -309,6 +311,11 @@ pub(crate) fn install_clone_disk<F: FnMut(Message)>(
         password_opt: password_opt.as_ref().map(|x| x.as_bytes()),
         efi_partition_size: None,
         skip_partitions: target.is_skip_partition(),
+        partition_scheme: Default::default(),
+        block_size: None,
+        partitions: &[],
+        reproducible: Default::default(),
+        shrink: false,
     };
 
     let disk_path = target.install_path();
@@ -368,6 +375,9 @@ pub(crate) fn install_clone_disk<F: FnMut(Message)>(
                 }
             };
 
+            // Embedded by an older installer, it may still point to the build host
+            config.remove_build_host_settings();
+
             // Copy filesystem.toml, which is not packaged
             let mut files = vec!["filesystem.toml".to_string()];
 
@@ -383,8 +393,8 @@ pub(crate) fn install_clone_disk<F: FnMut(Message)>(
 
             // Perform config install (after packages have been converted to files)
             message!("Configuring system");
-            let cookbook: Option<&'static str> = None;
-            redox_installer::install_dir(config, mount_path, cookbook)
+            let source = redox_installer::package_source(&config)?;
+            redox_installer::install_dir(config, mount_path, source.as_ref())
                 .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
 
             // Install files
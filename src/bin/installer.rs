@@ -1,17 +1,17 @@
 extern crate arg_parser;
 extern crate redox_installer;
 extern crate serde;
+extern crate serde_json;
 extern crate toml;
 
-use std::cell::RefCell;
-use std::path::Path;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
 use std::{env, fs, process};
 
 use arg_parser::ArgParser;
-use pkg::net_backend::DownloadBackend;
 
-use redox_installer::{Config, PackageConfig};
+#[cfg(feature = "compress")]
+use redox_installer::Compression;
+use redox_installer::{Config, ImageFormat, PackageConfig, PartitionSelector, Severity};
 
 const HELP_STR: &str = r#"
 redox_installer - Redox Installer.
@@ -20,21 +20,60 @@ redox_installer - Redox Installer.
 
 Using redox_installer as an installer:
   redox_installer <diskpath.img> [--config=file.toml] [--write-bootloader=file.img] [--live] [--no-mount] [--skip-partition]
-    <diskpath.img>        Disk file to write
+    <diskpath.img>        Disk file to write, or an existing partition to install into
+                          selected with gpt-guid:<uuid> or gpt-label:<name>
+    --disk                Disk or image to search for a gpt-guid or gpt-label partition,
+                          default is every disk of the system
+    --force               Format a gpt-guid or gpt-label partition whose type is not RedoxFS
     --config              Path to filesystem config TOML
     --write-bootloader    Path to write UEFI bootloader to in addition to the embedded ESP
     --skip-partition      Skip writing GPT partition tables
                           Use this only if you plan to use other partition tool
+    --alongside           Install into free space of an existing GPT disk, keeping its
+                          partitions and reusing its EFI system partition
+    --update              Apply the config to the RedoxFS of an existing disk, keeping its
+                          other files such as home directories
+    --format              Format of a new disk image: raw, qcow2, vmdk, vdi or iso
+                          Default is taken from the extension of <diskpath.img>, then raw
+    --compress            Compress the new image with zstd, xz or gzip, writing <diskpath.img>
+                          with the compression extension appended, and print its SHA-256
+    --block-size          Logical block size of a disk image, 512 (default) or 4096
+                          Block devices always use their own logical block size
     --live                Use bootloader configured for live disk
     --no-mount            Use RedoxFS AR instead of FUSE to write files
     --cookbook            Use local Redox OS build system rather than downloading packages
+    --package-dir         Use a directory of pkgar files and their id_ed25519.pub.toml
+                          rather than downloading packages
+    --cache-dir           Directory of the package download cache
+                          Default is $XDG_CACHE_HOME/redox_installer
+    --cache-only          Fail instead of downloading packages missing from the cache
     --config-name         Name of the filesystem configuration used for os-release VARIANT
+    --write-lockfile      Write the installed package versions and hashes to a lockfile
+    --locked              Refuse to install packages whose hash differs from the lockfile
+    --lockfile            Path of the lockfile, default is the config path with .lock extension
+    --dry-run             Print the partitions, packages, files and users that would be
+                          installed, without writing, downloading or mounting anything
+    --json                Print the --dry-run plan as JSON
 
 Using redox_installer as a configuration parser:
-  redox_installer --config=file.toml [--list-packages|--filesystem-size|--output-config path]
+  redox_installer --config=file.toml [--list-packages|--filesystem-size|--validate|--output-config path]
     --list-packages      List packages will be installed
-    --filesystem-size    Output filesystem size in MB
+    --validate           Check the config for mistakes without installing
+    --filesystem-size    Output filesystem size in MB, computing "auto" from the packages
+                         of the --cookbook, --package-dir or --cache-dir source
     --output-config      Path to write the parsed config as another TOML
+                         Unlike the embedded /filesystem.toml, this includes passwords
+
+Using redox_installer to grow an existing disk or image and its RedoxFS:
+  redox_installer resize <diskpath.img> [--size=4G] [--config=file.toml]
+    --size                New size of a raw disk image, in MB or with a K, M, G or T suffix
+                          Default is the current size of the disk, for disks grown by other tools
+    --block-size          Logical block size of a disk image, 512 (default) or 4096
+    --config              Config whose general.encrypt_disk unlocks an encrypted RedoxFS
+
+Using redox_installer to hash a user password:
+  redox_installer hash-password
+    Prompt for a password and print the value to use as users.<name>.password_hash
 "#;
 
 fn os_release_quote(value: &str) -> String {
@@ -56,27 +95,9 @@ fn build_id_from_repo_toml(repo_toml: &str) -> Option<String> {
         })
 }
 
-fn local_repo_build_id(cookbook: &str) -> Option<String> {
-    let repo_toml = Path::new(cookbook)
-        .join("repo")
-        .join(redox_installer::get_target())
-        .join("repo.toml");
-    let repo_toml = fs::read_to_string(repo_toml).ok()?;
-    build_id_from_repo_toml(&repo_toml)
-}
-
-fn remote_repo_build_id() -> Option<String> {
-    let callback = Rc::new(RefCell::new(pkg::callback::SilentCallback::new()));
-    let download_backend = pkg::net_backend::DefaultNetBackend::new().ok()?;
-    let mut repo = pkg::RepoManager::new(callback, Box::new(download_backend));
-    repo.add_remote(
-        "https://static.redox-os.org/pkg",
-        &redox_installer::get_target(),
-    )
-    .ok()?;
-
-    let package = pkg::PackageName::new("repo").ok()?;
-    let (repo_toml, _) = repo.get_package_toml(&package).ok()?;
+fn repo_build_id(config: &Config) -> Option<String> {
+    let source = redox_installer::package_source(config).ok()?;
+    let repo_toml = source.package_toml("repo").ok()?;
     build_id_from_repo_toml(&repo_toml)
 }
 
@@ -107,20 +128,130 @@ fn append_os_release_metadata(
     }
 }
 
+/// Installs to a temporary image next to the compressed output, then compresses it
+#[cfg(feature = "compress")]
+fn install_compressed(mut config: Config, path: &str, compression: Compression) {
+    if Path::new(path).is_dir() {
+        eprintln!("installer: {path}: --compress requires an image file");
+        process::exit(1);
+    }
+    let extension = format!(".{}", compression.extension());
+    let (image_name, output) = match path.strip_suffix(&extension) {
+        Some(image_name) => (image_name.to_string(), path.to_string()),
+        None => (path.to_string(), format!("{path}{extension}")),
+    };
+    // The image format still follows the name without the compression extension
+    config
+        .general
+        .image_format
+        .get_or_insert_with(|| ImageFormat::from_path(Path::new(&image_name)));
+    let image_path = PathBuf::from(format!("{output}.partial"));
+    if image_path.exists() {
+        if let Err(err) = fs::remove_file(&image_path) {
+            eprintln!("installer: {}: {err}", image_path.display());
+            process::exit(1);
+        }
+    }
+
+    if let Err(err) = redox_installer::install(config, &image_path) {
+        eprintln!("installer: failed to install: {:?}", err);
+        let _ = fs::remove_file(&image_path);
+        process::exit(1);
+    }
+    let compressed = match redox_installer::compress(&image_path, Path::new(&output), compression) {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            eprintln!("installer: failed to compress: {:?}", err);
+            let _ = fs::remove_file(&image_path);
+            let _ = fs::remove_file(&output);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = fs::remove_file(&image_path) {
+        eprintln!("installer: {}: {err}", image_path.display());
+    }
+    eprintln!(
+        "Wrote {output} with size {} ({} bytes)",
+        redox_installer::format_bytes(compressed.size),
+        compressed.size
+    );
+    // Same format as sha256sum
+    println!("{}  {output}", compressed.sha256);
+}
+
+/// Parses a size in MB, or in bytes with a K, M, G or T binary suffix
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, shift) = match size.trim_end_matches(['B', 'b']).trim_end_matches('i') {
+        number if number.ends_with(['K', 'k']) => (&number[..number.len() - 1], 10),
+        number if number.ends_with(['M', 'm']) => (&number[..number.len() - 1], 20),
+        number if number.ends_with(['G', 'g']) => (&number[..number.len() - 1], 30),
+        number if number.ends_with(['T', 't']) => (&number[..number.len() - 1], 40),
+        number => (number, 20),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{size}'"))
+}
+
 fn main() {
     let mut parser = ArgParser::new(4)
         .add_opt("b", "cookbook")
+        .add_opt("", "package-dir")
+        .add_opt("", "cache-dir")
+        .add_flag(&["cache-only"])
         .add_opt("", "config-name")
         .add_opt("c", "config")
         .add_opt("o", "output-config")
         .add_opt("", "write-bootloader")
+        .add_opt("", "lockfile")
+        .add_flag(&["write-lockfile"])
+        .add_flag(&["locked"])
+        .add_opt("", "format")
+        .add_opt("", "compress")
+        .add_opt("", "block-size")
+        .add_opt("", "size")
         .add_flag(&["skip-partition"])
+        .add_flag(&["alongside"])
+        .add_flag(&["update"])
+        .add_flag(&["force"])
+        .add_opt("", "disk")
         .add_flag(&["filesystem-size"])
         .add_flag(&["l", "list-packages"])
+        .add_flag(&["validate"])
+        .add_flag(&["dry-run"])
+        .add_flag(&["json"])
         .add_flag(&["live"])
         .add_flag(&["no-mount"]);
     parser.parse(env::args());
 
+    if parser
+        .args
+        .first()
+        .is_some_and(|arg| arg == "hash-password")
+    {
+        let password = match redox_installer::prompt_password("password: ", "confirm password: ") {
+            Ok(Some(password)) => password,
+            Ok(None) => {
+                eprintln!("\ninstaller: empty password");
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("\ninstaller: {err}");
+                process::exit(1);
+            }
+        };
+        match redox_installer::hash_password(&password) {
+            Ok(hash) => println!("\n{hash}"),
+            Err(err) => {
+                eprintln!("installer: failed to hash password: {err}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     let skip_partition = parser.found("skip-partition");
 
     let mut config = if let Some(path) = parser.get_opt("config") {
@@ -135,28 +266,154 @@ fn main() {
         redox_installer::Config::default()
     };
 
-    // Get toml of merged config
-    let merged_toml = toml::to_string_pretty(&config).unwrap();
+    if parser.args.first().is_some_and(|arg| arg == "resize") {
+        let Some(path) = parser.args.get(1) else {
+            eprintln!("installer: resize requires a disk path");
+            process::exit(1);
+        };
+        let size = match parser.get_opt("size").map(|size| parse_size(&size)) {
+            Some(Ok(size)) => Some(size),
+            Some(Err(err)) => {
+                eprintln!("installer: {err}");
+                process::exit(1);
+            }
+            None => None,
+        };
+        let block_size = match parser.get_opt("block-size").map(|size| size.parse::<u32>()) {
+            Some(Ok(block_size)) => Some(block_size),
+            Some(Err(err)) => {
+                eprintln!("installer: invalid block size: {err}");
+                process::exit(1);
+            }
+            None => None,
+        };
+        let password_opt = config.general.encrypt_disk.as_ref().map(|p| p.as_bytes());
+        if let Err(err) = redox_installer::resize(path, size, block_size, password_opt) {
+            eprintln!("installer: failed to resize: {:?}", err);
+            process::exit(1);
+        }
+        return;
+    }
 
-    // Just output merged config and exit
+    // Just output merged config and exit, secrets included
     if let Some(path) = parser.get_opt("output-config") {
+        let merged_toml = toml::to_string_pretty(&config).unwrap();
         fs::write(path, merged_toml).unwrap();
         return;
     }
 
-    // Add filesystem.toml to config
+    // Add filesystem.toml to config, with hashed passwords as it is world-readable
+    let redacted_toml = match config.redacted() {
+        Ok(redacted) => toml::to_string_pretty(&redacted).unwrap(),
+        Err(err) => {
+            eprintln!("installer: failed to hash passwords: {:?}", err);
+            process::exit(1);
+        }
+    };
     config.files.push(redox_installer::FileConfig {
-        path: "filesystem.toml".to_string(),
-        data: merged_toml,
+        path: "/filesystem.toml".to_string(),
+        data: redacted_toml,
         ..Default::default()
     });
 
     if skip_partition {
         config.general.skip_partitions = Some(true);
     }
+    if parser.found("alongside") {
+        config.general.alongside = Some(true);
+    }
+    if parser.found("update") {
+        config.general.update = Some(true);
+    }
+    if parser.found("force") {
+        config.general.force = Some(true);
+    }
+    if let Some(format) = parser.get_opt("format") {
+        match format.parse::<ImageFormat>() {
+            Ok(format) => config.general.image_format = Some(format),
+            Err(err) => {
+                eprintln!("installer: {err}");
+                process::exit(1);
+            }
+        }
+    }
+    #[cfg(feature = "compress")]
+    let compression = match parser.get_opt("compress").map(|c| c.parse::<Compression>()) {
+        Some(Ok(compression)) => Some(compression),
+        Some(Err(err)) => {
+            eprintln!("installer: {err}");
+            process::exit(1);
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "compress"))]
+    if parser.get_opt("compress").is_some() {
+        eprintln!("installer: --compress requires the compress feature");
+        process::exit(1);
+    }
+    if let Some(block_size) = parser.get_opt("block-size") {
+        match block_size.parse() {
+            Ok(block_size) => config.general.block_size = Some(block_size),
+            Err(err) => {
+                eprintln!("installer: invalid block size {block_size}: {err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    // Package sources, used by every branch below that reads packages
+    let cookbook = if let Some(path) = parser.get_opt("cookbook") {
+        if !Path::new(&path).is_dir() {
+            eprintln!("installer: {}: cookbook not found", path);
+            process::exit(1);
+        }
+
+        Some(path)
+    } else {
+        None
+    };
+
+    let package_dir = if let Some(path) = parser.get_opt("package-dir") {
+        if !Path::new(&path).is_dir() {
+            eprintln!("installer: {}: package directory not found", path);
+            process::exit(1);
+        }
+
+        Some(path)
+    } else {
+        config.general.package_dir.clone()
+    };
+
+    if cookbook.is_some() {
+        config.general.cookbook = cookbook;
+    }
+    config.general.package_dir = package_dir;
+    let cache_dir = parser.get_opt("cache-dir");
+    if cache_dir.is_some() {
+        config.general.cache_dir = cache_dir;
+    }
+    if parser.found("cache-only") {
+        config.general.cache_only = Some(true);
+    }
 
-    if parser.found("filesystem-size") {
-        println!("{}", config.general.filesystem_size.unwrap_or(0));
+    if parser.found("validate") {
+        let diagnostics = config.validate();
+        for diagnostic in &diagnostics {
+            println!("{diagnostic}");
+        }
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            process::exit(1);
+        }
+    } else if parser.found("filesystem-size") {
+        let size = redox_installer::package_source(&config)
+            .and_then(|source| redox_installer::filesystem_size(&config, source.as_ref()));
+        match size {
+            Ok(size) => println!("{size}"),
+            Err(err) => {
+                eprintln!("installer: failed to compute filesystem size: {:?}", err);
+                process::exit(1);
+            }
+        }
     } else if parser.found("list-packages") {
         // List the packages that should be fetched or built by the cookbook
         for (packagename, package) in &config.packages {
@@ -170,21 +427,12 @@ fn main() {
             }
         }
     } else {
-        let cookbook = if let Some(path) = parser.get_opt("cookbook") {
-            if !Path::new(&path).is_dir() {
-                eprintln!("installer: {}: cookbook not found", path);
-                process::exit(1);
-            }
-
-            Some(path)
-        } else {
+        // The build id needs the repo metadata, which a dry run does not fetch
+        let dry_run = parser.found("dry-run");
+        let build_id = if dry_run {
             None
-        };
-
-        let build_id = if let Some(cookbook) = cookbook.as_deref() {
-            local_repo_build_id(cookbook)
         } else {
-            remote_repo_build_id()
+            repo_build_id(&config)
         };
         append_os_release_metadata(
             &mut config,
@@ -192,9 +440,6 @@ fn main() {
             build_id,
         );
 
-        if cookbook.is_some() {
-            config.general.cookbook = cookbook;
-        }
         if parser.found("live") {
             config.general.live_disk = Some(true);
         }
@@ -205,11 +450,67 @@ fn main() {
         if write_bootloader.is_some() {
             config.general.write_bootloader = write_bootloader;
         }
+        if parser.found("write-lockfile") || parser.found("locked") {
+            let lockfile = parser.get_opt("lockfile").or_else(|| {
+                parser.get_opt("config").map(|path| {
+                    Path::new(&path)
+                        .with_extension("lock")
+                        .display()
+                        .to_string()
+                })
+            });
+            if lockfile.is_none() {
+                eprintln!("installer: --lockfile or --config is required to use a lockfile");
+                process::exit(1);
+            }
+            config.general.lockfile = lockfile;
+            config.general.locked = Some(parser.found("locked"));
+        }
 
         if let Some(path) = parser.args.first() {
-            if let Err(err) = redox_installer::install(config, path) {
-                eprintln!("installer: failed to install: {:?}", err);
-                process::exit(1);
+            if dry_run {
+                for diagnostic in config.validate() {
+                    eprintln!("{diagnostic}");
+                }
+                let plan = match redox_installer::install_plan(&config, path) {
+                    Ok(plan) => plan,
+                    Err(err) => {
+                        eprintln!("installer: failed to plan install: {:?}", err);
+                        process::exit(1);
+                    }
+                };
+                if parser.found("json") {
+                    println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+                } else {
+                    print!("{plan}");
+                }
+            } else if let Some(disk) = parser.get_opt("disk") {
+                let selector = match PartitionSelector::from_target(path) {
+                    Ok(Some(selector)) => selector,
+                    Ok(None) => {
+                        eprintln!("installer: --disk requires a gpt-guid or gpt-label target");
+                        process::exit(1);
+                    }
+                    Err(err) => {
+                        eprintln!("installer: {err}");
+                        process::exit(1);
+                    }
+                };
+                let disks = [PathBuf::from(disk)];
+                if let Err(err) = redox_installer::install_partition(config, &selector, &disks) {
+                    eprintln!("installer: failed to install: {:?}", err);
+                    process::exit(1);
+                }
+            } else {
+                #[cfg(feature = "compress")]
+                if let Some(compression) = compression {
+                    install_compressed(config, path, compression);
+                    return;
+                }
+                if let Err(err) = redox_installer::install(config, path) {
+                    eprintln!("installer: failed to install: {:?}", err);
+                    process::exit(1);
+                }
             }
         } else {
             eprint!("{}", HELP_STR);
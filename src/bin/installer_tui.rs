@@ -195,13 +195,24 @@ fn package_files(
 fn choose_disk() -> PathBuf {
     let mut paths = Vec::new();
     disk_paths(&mut paths);
+    // Mark disks that already contain RedoxFS, with the current or the legacy partition type
+    let labels: Vec<&str> = paths
+        .iter()
+        .map(
+            |(path, _)| match redox_installer::redoxfs_partitions(path) {
+                Ok(partitions) if !partitions.is_empty() => " (RedoxFS)",
+                _ => "",
+            },
+        )
+        .collect();
     loop {
         for (i, (path, size)) in paths.iter().enumerate() {
             eprintln!(
-                "\x1B[1m{}\x1B[0m: {}: {}",
+                "\x1B[1m{}\x1B[0m: {}: {}{}",
                 i + 1,
                 path.display(),
-                redox_installer::format_bytes(*size)
+                redox_installer::format_bytes(*size),
+                labels[i]
             );
         }
 
@@ -247,7 +258,7 @@ fn main() {
     let disk_path = choose_disk();
 
     let Ok(password_opt) = redox_installer::prompt_password(
-        "redox_installer_tui: redoxfs password (empty for none)",
+        "redox_installer_tui: disk encryption password (empty for none)",
         "redox_installer_tui: confirm password",
     ) else {
         process::exit(1);
@@ -299,6 +310,11 @@ fn main() {
         password_opt: password_opt.as_ref().map(|x| x.as_bytes()),
         efi_partition_size: None,
         skip_partitions: false, // TODO?
+        partition_scheme: Default::default(),
+        block_size: None,
+        partitions: &[],
+        reproducible: Default::default(),
+        shrink: false,
     };
     let res = with_whole_disk(&disk_path, &disk_option, |mut fs| {
         // Fast install method via filesystem clone
@@ -322,6 +338,8 @@ fn main() {
         // Slow install method via file copy
         with_redoxfs_mount(fs, None, |mount_path| {
             let mut config: Config = Config::from_file(&root_path.join("filesystem.toml"))?;
+            // Embedded by an older installer, it may still point to the build host
+            config.remove_build_host_settings();
 
             // Copy filesystem.toml, which is not packaged
             let mut files = vec!["filesystem.toml".to_string()];
@@ -333,8 +351,8 @@ fn main() {
 
             // Perform config install (after packages have been converted to files)
             eprintln!("configuring system");
-            let cookbook: Option<&'static str> = None;
-            redox_installer::install_dir(config, mount_path, cookbook)
+            let source = redox_installer::package_source(&config)?;
+            redox_installer::install_dir(config, mount_path, source.as_ref())
                 .map_err(|err| io::Error::other(err))?;
 
             // Sort and remove duplicates
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    thread,
+};
+
+/// Compression of a finished image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Xz,
+    Gzip,
+}
+
+impl Compression {
+    /// Extension appended to the name of compressed images
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gz",
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            _ => Err(format!(
+                "unknown compression '{s}', expected zstd, xz or gzip"
+            )),
+        }
+    }
+}
+
+/// Size and checksum of a compressed image
+#[derive(Clone, Debug)]
+pub struct Compressed {
+    pub size: u64,
+    /// SHA-256 of the compressed file, in lowercase hex
+    pub sha256: String,
+}
+
+/// Writer counting and hashing what is written to the compressed file
+struct HashWriter {
+    file: File,
+    size: u64,
+    hasher: Sha256,
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Streams an image through an encoder into a new file.
+/// Encoders use all CPUs, with output that does not depend on their number.
+pub fn compress(input: &Path, output: &Path, compression: Compression) -> Result<Compressed> {
+    let mut image =
+        File::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let image_size = image.metadata()?.len();
+    let writer = HashWriter {
+        file: File::create(output)
+            .with_context(|| format!("failed to create {}", output.display()))?,
+        size: 0,
+        hasher: Sha256::new(),
+    };
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get() as u32);
+
+    eprintln!(
+        "Compressing {} to {} with {compression}",
+        input.display(),
+        output.display()
+    );
+    let mut writer = match compression {
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            encoder.multithread(threads)?;
+            encoder.include_checksum(true)?;
+            encoder.set_pledged_src_size(Some(image_size))?;
+            io::copy(&mut image, &mut encoder)?;
+            encoder.finish()?
+        }
+        Compression::Xz => {
+            let stream = xz2::stream::MtStreamBuilder::new()
+                .threads(threads)
+                .preset(6)
+                .check(xz2::stream::Check::Crc64)
+                .encoder()?;
+            let mut encoder = xz2::write::XzEncoder::new_stream(writer, stream);
+            io::copy(&mut image, &mut encoder)?;
+            encoder.finish()?
+        }
+        Compression::Gzip => {
+            // The header has no file name and a zero modification time
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            io::copy(&mut image, &mut encoder)?;
+            encoder.finish()?
+        }
+    };
+    writer.flush()?;
+    writer.file.sync_all()?;
+
+    Ok(Compressed {
+        size: writer.size,
+        sha256: format!("{:x}", writer.hasher.finalize()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, io::Read, process};
+
+    #[test]
+    fn round_trip() {
+        let dir = env::temp_dir().join(format!("redox_installer_compress_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("disk.img");
+        // Compressible, with a part that is not, like a filesystem image
+        let mut image = vec![0; 1024 * 1024];
+        for (i, byte) in image.iter_mut().enumerate().take(64 * 1024) {
+            *byte = (i as u32).wrapping_mul(2_654_435_761).to_le_bytes()[3];
+        }
+        fs::write(&input, &image).unwrap();
+
+        for compression in [Compression::Zstd, Compression::Xz, Compression::Gzip] {
+            let output = dir.join(format!("disk.img.{}", compression.extension()));
+            let compressed = compress(&input, &output, compression).unwrap();
+
+            let data = fs::read(&output).unwrap();
+            assert_eq!(compressed.size, data.len() as u64, "{compression}");
+            assert_eq!(
+                compressed.sha256,
+                format!("{:x}", Sha256::digest(&data)),
+                "{compression}"
+            );
+
+            let mut decompressed = Vec::new();
+            match compression {
+                Compression::Zstd => {
+                    decompressed = zstd::decode_all(data.as_slice()).unwrap();
+                }
+                Compression::Xz => {
+                    xz2::read::XzDecoder::new(data.as_slice())
+                        .read_to_end(&mut decompressed)
+                        .unwrap();
+                }
+                Compression::Gzip => {
+                    flate2::read::GzDecoder::new(data.as_slice())
+                        .read_to_end(&mut decompressed)
+                        .unwrap();
+                }
+            }
+            assert!(decompressed == image, "{compression} round trip differs");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -29,6 +29,11 @@ fn chown<P: AsRef<Path>>(path: P, uid: uid_t, gid: gid_t, recursive: bool) -> Re
 // TODO: Rewrite impls
 impl crate::FileConfig {
     pub(crate) fn create<P: AsRef<Path>>(&self, prefix: P) -> Result<()> {
+        self.install(prefix, false)
+    }
+
+    /// Same as [Self::create], except that updates skip appends the file already ends with
+    pub(crate) fn install<P: AsRef<Path>>(&self, prefix: P, update: bool) -> Result<()> {
         let path = self.path.trim_start_matches('/');
         let target_file = prefix.as_ref().join(path);
 
@@ -60,6 +65,14 @@ impl crate::FileConfig {
             })?;
             Ok(())
         } else {
+            // Updated disks already have the appended data
+            if update
+                && self.append
+                && fs::read(&target_file).is_ok_and(|data| data.ends_with(self.data.as_bytes()))
+            {
+                println!("Skip append to {}, already done", target_file.display());
+                return self.apply_perms(target_file);
+            }
             let action = if self.append { "Append" } else { "Create" };
             println!("{action} file {}", target_file.display());
 
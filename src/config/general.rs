@@ -1,15 +1,144 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::partition::{PartitionScheme, SizeValue};
+
+/// Smallest filesystem_size in MB, approximately fitting just an initfs
+pub const MIN_FILESYSTEM_SIZE: u32 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "SizeValue", into = "SizeValue")]
+pub enum FilesystemSize {
+    /// Size in MB
+    Mb(u32),
+    /// Computed from the packages, files and users to install
+    Auto,
+}
+
+impl TryFrom<SizeValue> for FilesystemSize {
+    type Error = String;
+
+    fn try_from(value: SizeValue) -> Result<Self, Self::Error> {
+        match value {
+            SizeValue::Mib(size) => Ok(FilesystemSize::Mb(size)),
+            SizeValue::Keyword(keyword) if keyword == "auto" => Ok(FilesystemSize::Auto),
+            SizeValue::Keyword(keyword) => Err(format!(
+                "invalid filesystem size '{keyword}', expected a size in MB or \"auto\""
+            )),
+        }
+    }
+}
+
+impl From<FilesystemSize> for SizeValue {
+    fn from(size: FilesystemSize) -> Self {
+        match size {
+            FilesystemSize::Mb(size) => SizeValue::Mib(size),
+            FilesystemSize::Auto => SizeValue::Keyword("auto".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// Plain disk image, the only format of block devices
+    #[default]
+    Raw,
+    /// QEMU copy-on-write image, growing as it is written
+    Qcow2,
+    /// VMware monolithic sparse image
+    Vmdk,
+    /// VirtualBox dynamic image
+    Vdi,
+    /// Hybrid ISO 9660 image wrapping a raw disk image, bootable from optical media and USB drives
+    Iso,
+}
+
+impl ImageFormat {
+    /// Format of an output path by its extension, raw for unknown extensions
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.to_ascii_lowercase().parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vmdk => "vmdk",
+            ImageFormat::Vdi => "vdi",
+            ImageFormat::Iso => "iso",
+        })
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" | "img" => Ok(ImageFormat::Raw),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            "vmdk" => Ok(ImageFormat::Vmdk),
+            "vdi" => Ok(ImageFormat::Vdi),
+            "iso" => Ok(ImageFormat::Iso),
+            _ => Err(format!(
+                "unknown image format '{s}', expected raw, qcow2, vmdk, vdi or iso"
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GeneralConfig {
     /// Specify a path where cookbook exists, all packages will be installed locally
     pub cookbook: Option<String>,
+    /// Specify a directory of pkgar files and their id_ed25519.pub.toml to install packages from,
+    /// for builds without a cookbook or network access
+    pub package_dir: Option<String>,
+    /// Directory caching downloaded packages by hash, default to $XDG_CACHE_HOME/redox_installer
+    pub cache_dir: Option<String>,
+    /// Fail instead of downloading packages that are not in the cache
+    pub cache_only: Option<bool>,
     /// Allow prompts for missing information such as user password
     pub prompt: Option<bool>,
-    /// Total filesystem size in MB
-    pub filesystem_size: Option<u32>,
+    /// Total filesystem size in MB, or "auto" to fit the packages, files and users to install
+    pub filesystem_size: Option<FilesystemSize>,
+    /// Free space added to an "auto" filesystem_size, in percent of the content, default to 20
+    pub filesystem_headroom: Option<u32>,
     /// EFI partition size in MB, default to 2MB
     pub efi_partition_size: Option<u32>,
+    /// Format of new image files: "raw", "qcow2", "vmdk", "vdi" or "iso",
+    /// default to the extension of the output path, then raw
+    pub image_format: Option<ImageFormat>,
+    /// Partition table written to the disk, "gpt" (default) or "mbr"
+    pub partition_scheme: Option<PartitionScheme>,
+    /// Logical block size of image files, 512 or 4096, default to 512.
+    /// Block devices always use their own logical block size
+    pub block_size: Option<u32>,
     /// Skip disk partitioning, assume whole disk is a partition
     pub skip_partitions: Option<bool>,
+    /// Install into free space of an existing GPT disk, keeping its partitions
+    /// and adding the UEFI bootloader to its EFI system partition
+    pub alongside: Option<bool>,
+    /// First and last LBA of the free range to install into with alongside,
+    /// default to the largest free range of the disk
+    pub alongside_range: Option<[u64; 2]>,
+    /// Install into an existing partition selected by gpt-guid or gpt-label even if
+    /// its type is not RedoxFS, formatting it
+    pub force: Option<bool>,
+    /// Open the RedoxFS of an existing disk and apply the config to it, keeping other files
+    /// such as home directories. Partitions and bootloaders are left as they are
+    pub update: Option<bool>,
+    /// Derive partition and filesystem GUIDs and password salts from this seed, so that
+    /// installing the same config twice gives the same image. Timestamps come from the
+    /// SOURCE_DATE_EPOCH environment variable
+    pub reproducible_seed: Option<String>,
     /// Set a plain text password to encrypt the disk
     pub encrypt_disk: Option<String>,
     /// Use live disk for bootloader config, default is false
@@ -17,8 +146,13 @@ pub struct GeneralConfig {
     /// If set, write bootloader disk into this path
     pub write_bootloader: Option<String>,
     /// Use AR to write files instead of FUSE-based mount
-    /// (bypasses FUSE, but slower and requires namespaced context such as "podman unshare")
+    /// (bypasses FUSE, but slower and requires namespaced context such as "podman unshare").
+    /// Live disks written this way into a new raw image are cut down to their content
     pub no_mount: Option<bool>,
+    /// If set, write the resolved package set into this lockfile
+    pub lockfile: Option<String>,
+    /// Read the lockfile instead, refusing packages whose hash differs
+    pub locked: Option<bool>,
 }
 
 impl GeneralConfig {
@@ -27,9 +161,27 @@ impl GeneralConfig {
         if let Some(cookbook) = other.cookbook {
             self.cookbook = Some(cookbook);
         }
+        if let Some(package_dir) = other.package_dir {
+            self.package_dir = Some(package_dir);
+        }
+        if let Some(cache_dir) = other.cache_dir {
+            self.cache_dir = Some(cache_dir);
+        }
+        self.cache_only = other.cache_only.or(self.cache_only);
         self.filesystem_size = other.filesystem_size.or(self.filesystem_size);
+        self.filesystem_headroom = other.filesystem_headroom.or(self.filesystem_headroom);
         self.efi_partition_size = other.efi_partition_size.or(self.efi_partition_size);
+        self.image_format = other.image_format.or(self.image_format);
+        self.partition_scheme = other.partition_scheme.or(self.partition_scheme);
+        self.block_size = other.block_size.or(self.block_size);
         self.skip_partitions = other.skip_partitions.or(self.skip_partitions);
+        self.alongside = other.alongside.or(self.alongside);
+        self.alongside_range = other.alongside_range.or(self.alongside_range);
+        self.force = other.force.or(self.force);
+        self.update = other.update.or(self.update);
+        if let Some(reproducible_seed) = other.reproducible_seed {
+            self.reproducible_seed = Some(reproducible_seed);
+        }
         if let Some(encrypt_disk) = other.encrypt_disk {
             self.encrypt_disk = Some(encrypt_disk);
         }
@@ -38,5 +190,9 @@ impl GeneralConfig {
             self.write_bootloader = Some(write_bootloader);
         }
         self.no_mount = other.no_mount.or(self.no_mount);
+        if let Some(lockfile) = other.lockfile {
+            self.lockfile = Some(lockfile);
+        }
+        self.locked = other.locked.or(self.locked);
     }
 }
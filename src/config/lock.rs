@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+/// Resolved package set of an install, used to rebuild the same image later
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LockFile {
+    #[serde(default)]
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LockedPackage {
+    pub version: String,
+    /// Hash of the pkgar file, as listed in the repo, or of a local package file
+    pub blake3: String,
+    /// Repo the package was fetched from
+    pub source: String,
+    pub target: String,
+    /// Locked dependencies, so that locked installs need no repo metadata
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends: Vec<String>,
+}
+
+impl LockFile {
+    /// Load a lockfile from a TOML path
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("failed to read '{}'", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("failed to decode '{}'", path.display()))
+    }
+
+    /// Write the lockfile as TOML
+    pub fn to_file(&self, path: &Path) -> Result<()> {
+        let data = toml::to_string_pretty(self)?;
+        fs::write(path, data).with_context(|| format!("failed to write '{}'", path.display()))
+    }
+
+    /// Returns the locked packages and their locked dependencies
+    pub fn closure(&self, packages: &[String]) -> Result<BTreeMap<String, LockedPackage>> {
+        let mut closure = BTreeMap::new();
+        let mut queue = packages.to_vec();
+        while let Some(name) = queue.pop() {
+            if closure.contains_key(&name) {
+                continue;
+            }
+            let Some(locked) = self.packages.get(&name) else {
+                bail!("package {name} is not in the lockfile");
+            };
+            queue.extend(locked.depends.iter().cloned());
+            closure.insert(name, locked.clone());
+        }
+        Ok(closure)
+    }
+
+    /// Refuse any resolved package that is missing from this lockfile or has another hash
+    pub fn check(&self, resolved: &LockFile) -> Result<()> {
+        for (name, package) in &resolved.packages {
+            let Some(locked) = self.packages.get(name) else {
+                bail!("package {name} is not in the lockfile");
+            };
+            if locked.blake3 != package.blake3 {
+                bail!(
+                    "package {name}: hash {} ({}) differs from locked hash {} ({})",
+                    package.blake3,
+                    package.version,
+                    locked.blake3,
+                    locked.version
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn locked(blake3: &str, depends: &[&str]) -> LockedPackage {
+        LockedPackage {
+            version: "1.0.0".to_string(),
+            blake3: blake3.to_string(),
+            source: "https://static.redox-os.org/pkg".to_string(),
+            target: "x86_64-unknown-redox".to_string(),
+            depends: depends.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn lockfile() -> LockFile {
+        let mut lockfile = LockFile::default();
+        lockfile
+            .packages
+            .insert("ion".to_string(), locked("aa", &["relibc", "uutils"]));
+        lockfile
+            .packages
+            .insert("uutils".to_string(), locked("bb", &["relibc"]));
+        lockfile
+            .packages
+            .insert("relibc".to_string(), locked("cc", &[]));
+        lockfile
+            .packages
+            .insert("orbital".to_string(), locked("dd", &["relibc"]));
+        lockfile
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = env::temp_dir().join(format!("redox_installer_{}.lock", process::id()));
+        lockfile().to_file(&path).unwrap();
+        let read = LockFile::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!(read.packages.len(), 4);
+        let ion = &read.packages["ion"];
+        assert_eq!(ion.version, "1.0.0");
+        assert_eq!(ion.blake3, "aa");
+        assert_eq!(ion.source, "https://static.redox-os.org/pkg");
+        assert_eq!(ion.target, "x86_64-unknown-redox");
+        assert_eq!(ion.depends, ["relibc", "uutils"]);
+        assert!(read.packages["relibc"].depends.is_empty());
+        read.check(&lockfile()).unwrap();
+    }
+
+    #[test]
+    fn lockfile_without_depends() {
+        let read: LockFile = toml::from_str(
+            r#"
+            [packages.relibc]
+            version = "1.0.0"
+            blake3 = "cc"
+            source = "https://static.redox-os.org/pkg"
+            target = "x86_64-unknown-redox"
+            "#,
+        )
+        .unwrap();
+        assert!(read.packages["relibc"].depends.is_empty());
+    }
+
+    #[test]
+    fn closure() {
+        let closure = lockfile().closure(&["ion".to_string()]).unwrap();
+        let names: Vec<&String> = closure.keys().collect();
+        assert_eq!(names, ["ion", "relibc", "uutils"]);
+        assert_eq!(closure["uutils"].blake3, "bb");
+
+        let err = lockfile().closure(&["netstack".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "package netstack is not in the lockfile");
+    }
+
+    #[test]
+    fn check() {
+        let mut changed = lockfile();
+        changed.packages.get_mut("uutils").unwrap().blake3 = "ee".to_string();
+        assert!(lockfile().check(&changed).is_err());
+
+        let mut added = lockfile();
+        added
+            .packages
+            .insert("netstack".to_string(), locked("ff", &[]));
+        assert!(lockfile().check(&added).is_err());
+    }
+}
@@ -14,8 +14,12 @@ pub mod file;
 #[cfg(feature = "installer")]
 pub mod file_impl;
 pub mod general;
+pub mod lock;
 pub mod package;
+pub mod partition;
+pub mod repository;
 pub mod user;
+pub mod validate;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Config {
@@ -31,6 +35,10 @@ pub struct Config {
     pub users: BTreeMap<String, user::UserConfig>,
     #[serde(default)]
     pub groups: BTreeMap<String, user::GroupConfig>,
+    #[serde(default)]
+    pub repositories: Vec<repository::RepositoryConfig>,
+    #[serde(default)]
+    pub partitions: Vec<partition::PartitionConfig>,
 }
 
 impl Config {
@@ -50,6 +58,22 @@ impl Config {
 
         let config_dir = path.parent().unwrap();
 
+        // Local packages are relative to the config naming them, not to the working directory
+        for package in config.packages.values_mut() {
+            if let PackageConfig::Spec {
+                path: Some(path), ..
+            } = package
+            {
+                *path = config_dir.join(&path).to_string_lossy().into_owned();
+            }
+        }
+        // Same for the trusted keys of repositories
+        for repository in &mut config.repositories {
+            if let Some(public_key) = &mut repository.public_key {
+                *public_key = config_dir.join(&public_key).to_string_lossy().into_owned();
+            }
+        }
+
         let mut configs = mem::take(&mut config.include)
             .into_iter()
             .map(|path| {
@@ -71,18 +95,118 @@ impl Config {
     /// Load hardcoded install config to fetch bootloaders
     pub fn bootloader_config() -> Self {
         let mut bootloader_config = Config::default();
-        // TODO: This is unused
-        bootloader_config.files.push(file::FileConfig {
-            path: "/etc/pkg.d/50_redox".to_string(),
-            data: "https://static.redox-os.org/pkg".to_string(),
-            ..Default::default()
-        });
         bootloader_config
             .packages
             .insert("bootloader".to_string(), PackageConfig::default());
         bootloader_config
     }
 
+    /// Copy of this config that is safe to embed into the installed system,
+    /// with plain text user passwords replaced by their hash and the disk
+    /// encryption password removed. Live installers prompt for the latter.
+    /// Settings of the build host are removed as well
+    #[cfg(feature = "installer")]
+    pub fn redacted(&self) -> Result<Self> {
+        let reproducible = crate::Reproducible::new(self.general.reproducible_seed.as_deref())?;
+        let mut config = self.clone();
+        config.remove_build_host_settings();
+        config.general.encrypt_disk = None;
+        for (username, user) in config.users.iter_mut() {
+            if let Some(password) = user.password.take() {
+                user.password_hash = Some(crate::hash_password_salted(
+                    &password,
+                    &reproducible.salt(username)?,
+                )?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Removes paths and package sources that only exist on the build host, so that
+    /// an installed system reinstalling itself from this config uses remote repositories
+    pub fn remove_build_host_settings(&mut self) {
+        let general = &mut self.general;
+        general.cookbook = None;
+        general.package_dir = None;
+        general.cache_dir = None;
+        general.cache_only = None;
+        general.lockfile = None;
+        general.locked = None;
+        general.write_bootloader = None;
+
+        self.repositories
+            .retain(|repository| repository.local_path().is_none());
+        for repository in &mut self.repositories {
+            // Written into the image next to the pkg.d file of the repository
+            if repository.public_key.is_some() {
+                repository.public_key = Some(repository.pkg_d_key_path());
+            }
+        }
+
+        for package in self.packages.values_mut() {
+            if let PackageConfig::Spec { path, .. } = package {
+                *path = None;
+            }
+        }
+    }
+
+    /// UIDs and GIDs of users and groups, in the order the installer assigns them
+    pub fn assigned_ids(&self) -> user::AssignedIds {
+        let mut ids = user::AssignedIds::default();
+        let mut next_uid = 1000;
+        let mut next_gid = 1000;
+
+        for (username, user) in &self.users {
+            let uid = user.uid.unwrap_or(next_uid);
+
+            if uid >= next_uid {
+                next_uid = uid + 1;
+            }
+
+            let gid = user.gid.unwrap_or(next_gid);
+
+            if gid >= next_gid {
+                next_gid = gid + 1;
+            }
+
+            ids.users.insert(username.clone(), (uid, gid));
+        }
+
+        for (group, group_config) in &self.groups {
+            let gid = group_config.gid.unwrap_or(next_gid);
+
+            if gid >= next_gid {
+                next_gid = gid + 1;
+            }
+
+            ids.groups.insert(group.clone(), gid);
+        }
+
+        ids
+    }
+
+    /// Package repositories sorted by priority, defaulting to the official Redox repository
+    pub fn package_repositories(&self) -> Vec<repository::RepositoryConfig> {
+        if self.repositories.is_empty() {
+            return vec![repository::RepositoryConfig {
+                name: "redox".to_string(),
+                url: repository::REDOX_REPO_URL.to_string(),
+                ..Default::default()
+            }];
+        }
+        let mut repositories = self.repositories.clone();
+        repositories.sort_by_key(|repository| repository.priority());
+        repositories
+    }
+
+    /// Partitions of a whole disk install, defaulting to the BIOS, EFI and RedoxFS layout
+    pub fn partition_layout(&self) -> Vec<partition::PartitionConfig> {
+        if self.partitions.is_empty() {
+            return partition::PartitionConfig::default_layout(self.general.efi_partition_size);
+        }
+        self.partitions.clone()
+    }
+
     pub fn merge(&mut self, other: Config) {
         assert!(self.include.is_empty());
         assert!(other.include.is_empty());
@@ -94,6 +218,8 @@ impl Config {
             files: other_files,
             users: other_users,
             groups: other_groups,
+            repositories: other_repositories,
+            partitions: other_partitions,
         } = other;
 
         self.general.merge(other_general);
@@ -111,6 +237,17 @@ impl Config {
         for (group, group_config) in other_groups {
             self.groups.insert(group, group_config);
         }
+
+        for repository in other_repositories {
+            self.repositories
+                .retain(|existing| existing.name != repository.name);
+            self.repositories.push(repository);
+        }
+
+        // A layout only makes sense as a whole
+        if !other_partitions.is_empty() {
+            self.partitions = other_partitions;
+        }
     }
 }
 
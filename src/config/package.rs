@@ -6,8 +6,12 @@ pub enum PackageConfig {
 
     // TODO: Sum type
     Spec {
+        /// Exact version the repo must provide, or the install fails
         version: Option<String>,
+        /// Source repository, which the installer cannot build from and refuses
         git: Option<String>,
+        /// Local pkgar file to install instead of fetching from the repo,
+        /// relative to the config file that sets it
         path: Option<String>,
     },
 }
@@ -0,0 +1,120 @@
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionScheme {
+    /// GUID partition table behind a protective MBR
+    #[default]
+    Gpt,
+    /// Legacy MBR partition table, for BIOS firmware refusing GPT disks
+    Mbr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionFilesystem {
+    /// The partition RedoxFS is installed to, exactly one is required
+    Redoxfs,
+    /// FAT filesystem, receiving the UEFI bootloader when the type is "efi"
+    Fat32,
+    /// Written as-is from contents, if any
+    Raw,
+    /// Left untouched, such as the BIOS boot partition
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "SizeValue", into = "SizeValue")]
+pub enum PartitionSize {
+    /// Size in MiB
+    Mib(u32),
+    /// Remaining space of the disk
+    Rest,
+}
+
+/// Size as written in TOML, a number or a keyword
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub(super) enum SizeValue {
+    Mib(u32),
+    Keyword(String),
+}
+
+impl TryFrom<SizeValue> for PartitionSize {
+    type Error = String;
+
+    fn try_from(value: SizeValue) -> Result<Self, Self::Error> {
+        match value {
+            SizeValue::Mib(size) => Ok(PartitionSize::Mib(size)),
+            SizeValue::Keyword(keyword) if keyword == "rest" => Ok(PartitionSize::Rest),
+            SizeValue::Keyword(keyword) => Err(format!(
+                "invalid partition size '{keyword}', expected a size in MiB or \"rest\""
+            )),
+        }
+    }
+}
+
+impl From<PartitionSize> for SizeValue {
+    fn from(size: PartitionSize) -> Self {
+        match size {
+            PartitionSize::Mib(size) => SizeValue::Mib(size),
+            PartitionSize::Rest => SizeValue::Keyword("rest".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartitionConfig {
+    /// GPT partition name
+    pub name: String,
+    /// GPT partition type: "bios", "efi", "redoxfs", "linux" or a type GUID.
+    /// Use "linux" for RedoxFS partitions read by tools predating the "redoxfs" type
+    #[serde(rename = "type")]
+    pub part_type: String,
+    pub size: PartitionSize,
+    pub filesystem: PartitionFilesystem,
+    /// Path on the build host of an image to write into a raw partition,
+    /// or of a directory to copy into a fat32 partition
+    pub contents: Option<String>,
+}
+
+impl PartitionConfig {
+    /// Layout used when no partitions are configured: the BIOS boot partition
+    /// in the first MiB, then the EFI partition, then RedoxFS on the rest of the disk
+    pub fn default_layout(efi_partition_size: Option<u32>) -> Vec<PartitionConfig> {
+        vec![
+            PartitionConfig {
+                name: "BIOS".to_string(),
+                part_type: "bios".to_string(),
+                size: PartitionSize::Mib(1),
+                filesystem: PartitionFilesystem::None,
+                contents: None,
+            },
+            PartitionConfig {
+                name: "EFI".to_string(),
+                part_type: "efi".to_string(),
+                size: PartitionSize::Mib(efi_partition_size.unwrap_or(1)),
+                filesystem: PartitionFilesystem::Fat32,
+                contents: None,
+            },
+            PartitionConfig {
+                name: "REDOX".to_string(),
+                part_type: "redoxfs".to_string(),
+                size: PartitionSize::Rest,
+                filesystem: PartitionFilesystem::Redoxfs,
+                contents: None,
+            },
+        ]
+    }
+}
+
+/// Partition type names understood by the installer, or a GUID such as
+/// "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+pub fn is_partition_type(part_type: &str) -> bool {
+    if matches!(part_type, "bios" | "efi" | "redoxfs" | "linux") {
+        return true;
+    }
+    let groups: Vec<&str> = part_type.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
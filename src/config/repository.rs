@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use super::file::FileConfig;
+
+/// Official Redox package repository, used when no repository is configured
+pub const REDOX_REPO_URL: &str = "https://static.redox-os.org/pkg";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RepositoryConfig {
+    /// Name of the repository, written into the image as /etc/pkg.d/{priority}_{name}.
+    /// Name "redox" with the default priority replaces the 50_redox file of the official repository
+    pub name: String,
+    /// Remote URL, or file:// path of a local repository
+    pub url: String,
+    /// Repositories with lower priority are tried first, default to 50
+    pub priority: Option<u32>,
+    /// Path to the trusted id_ed25519.pub.toml, relative to the config file. Required for
+    /// file:// repositories, where the file must have this name.
+    /// Packages of remote repositories are verified with it instead of the key they serve
+    pub public_key: Option<String>,
+}
+
+impl RepositoryConfig {
+    pub fn priority(&self) -> u32 {
+        self.priority.unwrap_or(50)
+    }
+
+    /// Path of a file:// repository on the build host
+    pub fn local_path(&self) -> Option<&Path> {
+        self.url.strip_prefix("file://").map(Path::new)
+    }
+
+    /// Path of the pkg.d file for this repository inside the image
+    pub fn pkg_d_path(&self) -> String {
+        format!("/etc/pkg.d/{:02}_{}", self.priority(), self.name)
+    }
+
+    /// Path of the trusted public key of a remote repository inside the image
+    pub fn pkg_d_key_path(&self) -> String {
+        format!("{}.pub.toml", self.pkg_d_path())
+    }
+
+    /// Files of a remote repository inside the image: its pkg.d file and
+    /// the trusted public key next to it. Repositories of the build host have none
+    pub fn pkg_d_files(&self) -> Result<Vec<FileConfig>> {
+        if self.local_path().is_some() {
+            return Ok(Vec::new());
+        }
+        let mut files = vec![FileConfig::new_file(
+            self.pkg_d_path(),
+            format!("{}\n", self.url),
+        )];
+        if let Some(public_key) = &self.public_key {
+            let key = fs::read_to_string(public_key).with_context(|| {
+                format!("repository {}: failed to read {public_key}", self.name)
+            })?;
+            files.push(FileConfig::new_file(self.pkg_d_key_path(), key));
+        }
+        Ok(files)
+    }
+}
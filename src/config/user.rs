@@ -1,8 +1,12 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserConfig {
     pub password: Option<String>,
+    /// Argon2 encoded hash as produced by `redox_installer hash-password`,
+    /// used instead of a plain text `password`
+    pub password_hash: Option<String>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
     pub name: Option<String>,
@@ -10,6 +14,15 @@ pub struct UserConfig {
     pub shell: Option<String>,
 }
 
+/// IDs given by the installer, counting from 1000 for users and groups without one
+#[derive(Clone, Debug, Default)]
+pub struct AssignedIds {
+    /// UID and GID of each user, who also gets a group of the same name
+    pub users: BTreeMap<String, (u32, u32)>,
+    /// GID of each group
+    pub groups: BTreeMap<String, u32>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GroupConfig {
     pub gid: Option<u32>,
@@ -34,7 +47,9 @@ impl Display for UserConfig {
         if let Some(shell) = &self.shell {
             write!(f, " shell={}", shell)?;
         }
-        if self.password.as_ref().is_some_and(|s| !s.is_empty()) {
+        if self.password.as_ref().is_some_and(|s| !s.is_empty())
+            || self.password_hash.as_ref().is_some_and(|s| !s.is_empty())
+        {
             write!(f, " password=yes")?;
         }
 
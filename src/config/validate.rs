@@ -0,0 +1,576 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Component, Path};
+
+use super::general::{FilesystemSize, ImageFormat, MIN_FILESYSTEM_SIZE};
+use super::package::PackageConfig;
+use super::partition::{is_partition_type, PartitionFilesystem, PartitionScheme, PartitionSize};
+use super::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Config key the diagnostic is about, such as "users.user.uid"
+    pub location: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.location, self.message)
+    }
+}
+
+impl Config {
+    /// Check the config for mistakes that would fail or misbehave during install
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        self.validate_general(&mut diagnostics);
+        self.validate_users(&mut diagnostics);
+        self.validate_files(&mut diagnostics);
+        self.validate_packages(&mut diagnostics);
+        self.validate_partitions(&mut diagnostics);
+        diagnostics
+    }
+
+    fn validate_general(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let general = &self.general;
+        if let Some(FilesystemSize::Mb(size)) = general.filesystem_size {
+            if size < MIN_FILESYSTEM_SIZE {
+                diagnostics.push(Diagnostic::error(
+                    "general.filesystem_size",
+                    format!("{size} MB is below the minimum of {MIN_FILESYSTEM_SIZE} MB"),
+                ));
+            }
+        }
+        if general.filesystem_headroom.is_some()
+            && general.filesystem_size != Some(FilesystemSize::Auto)
+        {
+            diagnostics.push(Diagnostic::warning(
+                "general.filesystem_headroom",
+                "only used with filesystem_size = \"auto\"",
+            ));
+        }
+        if let Some(block_size) = general.block_size {
+            if block_size != 512 && block_size != 4096 {
+                diagnostics.push(Diagnostic::error(
+                    "general.block_size",
+                    format!("{block_size} is not supported, use 512 or 4096"),
+                ));
+            }
+        }
+        if general.partition_scheme == Some(PartitionScheme::Mbr) {
+            if general.alongside.unwrap_or(false) {
+                diagnostics.push(Diagnostic::error(
+                    "general.partition_scheme",
+                    "alongside requires an existing GPT disk",
+                ));
+            }
+            let entries = self
+                .partition_layout()
+                .iter()
+                .filter(|partition| partition.part_type != "bios")
+                .count();
+            if entries > 4 {
+                diagnostics.push(Diagnostic::error(
+                    "partitions",
+                    format!(
+                        "MBR holds at most 4 partitions besides the BIOS area, found {entries}"
+                    ),
+                ));
+            }
+            for (i, partition) in self.partitions.iter().enumerate() {
+                if !matches!(
+                    partition.part_type.as_str(),
+                    "bios" | "efi" | "redoxfs" | "linux"
+                ) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("partitions[{i}] {}", partition.name),
+                        "GUID types cannot be written to an MBR partition table",
+                    ));
+                }
+            }
+        }
+        if general.alongside.unwrap_or(false) {
+            if general.skip_partitions.unwrap_or(false) {
+                diagnostics.push(Diagnostic::error(
+                    "general.alongside",
+                    "cannot be combined with skip_partitions",
+                ));
+            }
+            if !self.partitions.is_empty() {
+                diagnostics.push(Diagnostic::warning(
+                    "partitions",
+                    "ignored with general.alongside, which adds a single RedoxFS partition",
+                ));
+            }
+        } else if general.alongside_range.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "general.alongside_range",
+                "ignored unless general.alongside is set",
+            ));
+        }
+        if let Some([first, last]) = general.alongside_range {
+            if first > last {
+                diagnostics.push(Diagnostic::error(
+                    "general.alongside_range",
+                    format!("first LBA {first} is after last LBA {last}"),
+                ));
+            }
+        }
+        if general.update.unwrap_or(false) {
+            if general.alongside.unwrap_or(false) {
+                diagnostics.push(Diagnostic::error(
+                    "general.update",
+                    "cannot be combined with alongside",
+                ));
+            }
+            if general.no_mount.unwrap_or(false) {
+                diagnostics.push(Diagnostic::error(
+                    "general.update",
+                    "RedoxFS AR only writes new filesystems, updating requires a mount",
+                ));
+            }
+            if general.image_format == Some(ImageFormat::Iso) {
+                diagnostics.push(Diagnostic::error(
+                    "general.update",
+                    "ISO images are written anew",
+                ));
+            }
+        }
+        if general.image_format == Some(ImageFormat::Iso) {
+            if general.alongside.unwrap_or(false) || general.skip_partitions.unwrap_or(false) {
+                diagnostics.push(Diagnostic::error(
+                    "general.image_format",
+                    "ISO images need their own partitions, without alongside or skip_partitions",
+                ));
+            }
+            if general.block_size.is_some_and(|size| size != 512) {
+                diagnostics.push(Diagnostic::error(
+                    "general.block_size",
+                    "ISO images boot as a disk of 512-byte blocks",
+                ));
+            }
+        }
+        if general.reproducible_seed.is_some() && general.encrypt_disk.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "general.reproducible_seed",
+                "encrypted disks use random keys and are not reproducible",
+            ));
+        }
+        if general.skip_partitions.unwrap_or(false) && general.efi_partition_size.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "general.efi_partition_size",
+                "no EFI partition is created when skip_partitions is set",
+            ));
+        }
+    }
+
+    fn validate_users(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let ids = self.assigned_ids();
+
+        let mut uids: BTreeMap<u32, &str> = BTreeMap::new();
+        for (username, (uid, _)) in &ids.users {
+            if let Some(other) = uids.insert(*uid, username) {
+                diagnostics.push(Diagnostic::error(
+                    format!("users.{username}"),
+                    format!("UID {uid} is also used by user {other}"),
+                ));
+            }
+        }
+
+        // Every user gets a group of the same name
+        let mut gids: BTreeMap<u32, String> = BTreeMap::new();
+        let user_groups = ids.users.iter().map(|(name, (_, gid))| (name, *gid));
+        let groups = ids.groups.iter().map(|(name, gid)| (name, *gid));
+        for (name, gid) in user_groups.chain(groups) {
+            if let Some(other) = gids.insert(gid, name.clone()) {
+                diagnostics.push(Diagnostic::error(
+                    format!("groups.{name}"),
+                    format!("GID {gid} is also used by group {other}"),
+                ));
+            }
+        }
+
+        for (username, user) in &self.users {
+            if user.password.is_some() && user.password_hash.is_some() {
+                diagnostics.push(Diagnostic::error(
+                    format!("users.{username}"),
+                    "password and password_hash are mutually exclusive",
+                ));
+            }
+        }
+
+        for (group, group_config) in &self.groups {
+            if self.users.contains_key(group) {
+                diagnostics.push(Diagnostic::error(
+                    format!("groups.{group}"),
+                    format!("conflicts with the group created for user {group}"),
+                ));
+            }
+            for member in &group_config.members {
+                if !self.users.contains_key(member) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("groups.{group}.members"),
+                        format!("user {member} does not exist"),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn validate_files(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (i, file) in self.files.iter().enumerate() {
+            let location = format!("files[{i}] {}", file.path);
+            let path = Path::new(&file.path);
+            if path.components().any(|c| c == Component::ParentDir) {
+                diagnostics.push(Diagnostic::error(
+                    location.clone(),
+                    "path must not contain '..'",
+                ));
+            } else if !path.is_absolute() {
+                diagnostics.push(Diagnostic::warning(
+                    location.clone(),
+                    "path should be absolute",
+                ));
+            }
+            if file.symlink && file.mode.is_some() {
+                diagnostics.push(Diagnostic::warning(
+                    location,
+                    "mode is ignored for symlinks",
+                ));
+            }
+        }
+    }
+
+    fn validate_packages(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (name, package) in &self.packages {
+            let PackageConfig::Spec { version, git, path } = package else {
+                continue;
+            };
+            if path.is_some() && version.is_some() {
+                diagnostics.push(Diagnostic::error(
+                    format!("packages.{name}"),
+                    "version cannot be checked for a local path, set only one of them",
+                ));
+            }
+            if git.is_some() {
+                diagnostics.push(Diagnostic::error(
+                    format!("packages.{name}.git"),
+                    "not supported by the installer, build the package and set its path instead",
+                ));
+            }
+        }
+    }
+
+    fn validate_partitions(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if self.partitions.is_empty() {
+            return;
+        }
+        if self.general.efi_partition_size.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "general.efi_partition_size",
+                "ignored when partitions are configured, set the size of the EFI partition instead",
+            ));
+        }
+
+        let mut names = BTreeMap::new();
+        let mut rest = 0;
+        let mut redoxfs = 0;
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let location = format!("partitions[{i}] {}", partition.name);
+            if let Some(other) = names.insert(partition.name.as_str(), i) {
+                diagnostics.push(Diagnostic::warning(
+                    location.clone(),
+                    format!("name is also used by partitions[{other}]"),
+                ));
+            }
+            if partition.name.encode_utf16().count() > 36 {
+                diagnostics.push(Diagnostic::error(
+                    location.clone(),
+                    "GPT partition names are limited to 36 UTF-16 characters",
+                ));
+            }
+            if !is_partition_type(&partition.part_type) {
+                diagnostics.push(Diagnostic::error(
+                    location.clone(),
+                    format!(
+                        "unknown type '{}', expected bios, efi, redoxfs, linux or a GUID",
+                        partition.part_type
+                    ),
+                ));
+            }
+            match partition.size {
+                PartitionSize::Mib(0) => {
+                    diagnostics.push(Diagnostic::error(location.clone(), "size must not be zero"))
+                }
+                PartitionSize::Mib(_) => (),
+                PartitionSize::Rest => rest += 1,
+            }
+            match partition.filesystem {
+                PartitionFilesystem::Redoxfs => redoxfs += 1,
+                PartitionFilesystem::Raw | PartitionFilesystem::Fat32 => (),
+                PartitionFilesystem::None => {
+                    if partition.contents.is_some() {
+                        diagnostics.push(Diagnostic::error(
+                            location.clone(),
+                            "contents requires a raw or fat32 filesystem",
+                        ));
+                    }
+                }
+            }
+            if partition.filesystem == PartitionFilesystem::Redoxfs && partition.contents.is_some()
+            {
+                diagnostics.push(Diagnostic::error(
+                    location,
+                    "contents of the RedoxFS partition come from packages and files",
+                ));
+            }
+        }
+        if rest > 1 {
+            diagnostics.push(Diagnostic::error(
+                "partitions",
+                "only one partition can use the rest of the disk",
+            ));
+        }
+        if redoxfs != 1 {
+            diagnostics.push(Diagnostic::error(
+                "partitions",
+                format!("exactly one redoxfs partition is required, found {redoxfs}"),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(config: &str) -> Vec<Diagnostic> {
+        toml::from_str::<Config>(config).unwrap().validate()
+    }
+
+    /// Whether a diagnostic has this severity and location
+    fn has(diagnostics: &[Diagnostic], severity: Severity, location: &str) -> bool {
+        diagnostics
+            .iter()
+            .any(|d| d.severity == severity && d.location == location)
+    }
+
+    #[test]
+    fn valid_config() {
+        let diagnostics = validate(
+            r#"
+            [general]
+            filesystem_size = 1024
+            block_size = 4096
+
+            [users.user]
+            password = "password"
+
+            [groups.sudo]
+            members = ["user"]
+
+            [[files]]
+            path = "/etc/hostname"
+            data = "redox"
+            "#,
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn duplicate_uid() {
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            uid = 1000
+            [users.other]
+            uid = 1000
+            gid = 1001
+            "#,
+        );
+        assert!(has(&diagnostics, Severity::Error, "users.user"));
+
+        // Users without a UID are numbered after those with one
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            uid = 1000
+            [users.zed]
+            "#,
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn duplicate_gid() {
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            gid = 1000
+            [groups.sudo]
+            gid = 1000
+            members = []
+            "#,
+        );
+        assert!(has(&diagnostics, Severity::Error, "groups.sudo"));
+
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            gid = 1000
+            [groups.sudo]
+            gid = 1001
+            members = []
+            "#,
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn password_and_hash() {
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            password = "password"
+            password_hash = "$argon2i$v=19$m=4096,t=10,p=1$c2FsdA$aGFzaA"
+            "#,
+        );
+        assert!(has(&diagnostics, Severity::Error, "users.user"));
+
+        let diagnostics = validate(
+            r#"
+            [users.user]
+            password_hash = "$argon2i$v=19$m=4096,t=10,p=1$c2FsdA$aGFzaA"
+            "#,
+        );
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn file_paths() {
+        let diagnostics = validate(
+            r#"
+            [[files]]
+            path = "/etc/../../host"
+            data = ""
+
+            [[files]]
+            path = "etc/hostname"
+            data = ""
+            "#,
+        );
+        assert!(has(
+            &diagnostics,
+            Severity::Error,
+            "files[0] /etc/../../host"
+        ));
+        assert!(has(
+            &diagnostics,
+            Severity::Warning,
+            "files[1] etc/hostname"
+        ));
+        assert_eq!(diagnostics.len(), 2, "{diagnostics:?}");
+    }
+
+    #[test]
+    fn mbr_limits() {
+        let partition = |name: &str, part_type: &str, size: &str, filesystem: &str| {
+            format!(
+                "[[partitions]]\nname = \"{name}\"\ntype = \"{part_type}\"\nsize = {size}\nfilesystem = \"{filesystem}\"\n"
+            )
+        };
+        let mut config = "[general]\npartition_scheme = \"mbr\"\n".to_string();
+        config += &partition("BIOS", "bios", "1", "none");
+        config += &partition("EFI", "efi", "1", "fat32");
+        for i in 0..3 {
+            config += &partition(&format!("DATA{i}"), "linux", "1", "raw");
+        }
+        config += &partition("REDOX", "redoxfs", "\"rest\"", "redoxfs");
+        let diagnostics = validate(&config);
+        assert!(has(&diagnostics, Severity::Error, "partitions"));
+
+        // The BIOS area is not an MBR entry, leaving room for four partitions
+        let mut config = "[general]\npartition_scheme = \"mbr\"\n".to_string();
+        config += &partition("BIOS", "bios", "1", "none");
+        config += &partition("EFI", "efi", "1", "fat32");
+        for i in 0..2 {
+            config += &partition(&format!("DATA{i}"), "linux", "1", "raw");
+        }
+        config += &partition("REDOX", "redoxfs", "\"rest\"", "redoxfs");
+        let diagnostics = validate(&config);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+        let mut config = "[general]\npartition_scheme = \"mbr\"\n".to_string();
+        config += &partition("DATA", "0FC63DAF-8483-4772-8E79-3D69D8477DE4", "1", "raw");
+        config += &partition("REDOX", "redoxfs", "\"rest\"", "redoxfs");
+        let diagnostics = validate(&config);
+        assert!(has(&diagnostics, Severity::Error, "partitions[0] DATA"));
+
+        let diagnostics = validate("[general]\npartition_scheme = \"mbr\"\nalongside = true\n");
+        assert!(has(
+            &diagnostics,
+            Severity::Error,
+            "general.partition_scheme"
+        ));
+    }
+
+    #[test]
+    fn block_size() {
+        let diagnostics = validate("[general]\nblock_size = 1024\n");
+        assert!(has(&diagnostics, Severity::Error, "general.block_size"));
+
+        for block_size in [512, 4096] {
+            let diagnostics = validate(&format!("[general]\nblock_size = {block_size}\n"));
+            assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        }
+
+        let diagnostics = validate("[general]\nblock_size = 4096\nimage_format = \"iso\"\n");
+        assert!(has(&diagnostics, Severity::Error, "general.block_size"));
+    }
+
+    #[test]
+    fn skip_partitions_with_efi() {
+        let diagnostics = validate("[general]\nskip_partitions = true\nefi_partition_size = 16\n");
+        assert!(has(
+            &diagnostics,
+            Severity::Warning,
+            "general.efi_partition_size"
+        ));
+
+        let diagnostics = validate("[general]\nskip_partitions = true\n");
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+        let diagnostics = validate("[general]\nskip_partitions = true\nalongside = true\n");
+        assert!(has(&diagnostics, Severity::Error, "general.alongside"));
+    }
+}
@@ -1,14 +1,145 @@
 use std::{
     cmp,
     convert::TryInto,
-    fs::{File, OpenOptions},
+    fs::{File, Metadata, OpenOptions},
     io::{Read, Result, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
     path::Path,
 };
 
+use crate::image::{self, VirtualDisk};
+
+/// Logical block size of image files when none is configured
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Returns the size and logical block size of a block device or image file.
+/// Image files have no block size of their own, so image_block_size is used.
+pub fn disk_geometry(disk: &mut File, image_block_size: Option<usize>) -> Result<(u64, usize)> {
+    let metadata = disk.metadata()?;
+    if metadata.is_file() {
+        // Virtual machine images contain a disk of another size than the file
+        let size = match image::open(disk.try_clone()?)? {
+            Some(image) => image.size(),
+            None => metadata.len(),
+        };
+        return Ok((size, image_block_size.unwrap_or(DEFAULT_BLOCK_SIZE)));
+    }
+
+    // Block devices may not report their size in metadata
+    let size = match metadata.len() {
+        0 => {
+            let size = disk.seek(SeekFrom::End(0))?;
+            disk.seek(SeekFrom::Start(0))?;
+            size
+        }
+        len => len,
+    };
+    Ok((size, device_block_size(disk, &metadata)?))
+}
+
+#[cfg(target_os = "linux")]
+fn device_block_size(disk: &File, _metadata: &Metadata) -> Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    const BLKSSZGET: u64 = 0x1268;
+    let mut block_size: libc::c_int = 0;
+    if unsafe { libc::ioctl(disk.as_raw_fd(), BLKSSZGET as _, &mut block_size) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(block_size as usize)
+}
+
+#[cfg(target_os = "redox")]
+fn device_block_size(_disk: &File, metadata: &Metadata) -> Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Unlike image files, disk schemes report their logical block size
+    Ok(metadata.blksize() as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "redox")))]
+fn device_block_size(_disk: &File, _metadata: &Metadata) -> Result<usize> {
+    Ok(DEFAULT_BLOCK_SIZE)
+}
+
+/// Deallocates a block aligned range of an image file, which then reads as zeros
+#[cfg(target_os = "linux")]
+fn punch_hole(disk: &File, offset: u64, len: u64) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(disk.as_raw_fd(), mode, offset as _, len as _) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_disk: &File, _offset: u64, _len: u64) -> Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Block device or raw image file
+#[derive(Debug)]
+struct RawDisk {
+    file: File,
+    size: u64,
+    block_len: usize,
+    /// Image files keep zero blocks as holes, block devices are written as-is.
+    /// Cleared if the filesystem of the image cannot punch holes.
+    sparse: bool,
+}
+
+impl VirtualDisk for RawDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    /// Writes whole blocks, punching holes for runs of zero blocks if sparse is set
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if !self.sparse {
+            return self.file.write_all_at(data, offset);
+        }
+
+        let block_len = self.block_len;
+        let is_zero = |start: usize| data[start..start + block_len].iter().all(|&b| b == 0);
+        let mut start = 0;
+        while start < data.len() {
+            let zero = is_zero(start);
+            let mut end = start + block_len;
+            while end < data.len() && is_zero(end) == zero {
+                end += block_len;
+            }
+
+            let run_offset = offset + start as u64;
+            if zero && self.sparse {
+                match punch_hole(&self.file, run_offset, (end - start) as u64) {
+                    Ok(()) => {
+                        start = end;
+                        continue;
+                    }
+                    // Write zeros instead, and stop trying
+                    Err(_) => self.sparse = false,
+                }
+            }
+            self.file.write_all_at(&data[start..end], run_offset)?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
 #[derive(Debug)]
 pub struct DiskWrapper {
-    disk: File,
+    disk: Box<dyn VirtualDisk>,
     size: u64,
     block: Box<[u8]>,
     seek: u64,
@@ -20,13 +151,38 @@ enum Buffer<'a> {
 }
 
 impl DiskWrapper {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let disk = OpenOptions::new().read(true).write(true).open(path)?;
-        let metadata = disk.metadata()?;
-        let size = metadata.len();
-        // TODO: get real block size: disk_metadata.blksize() works on disks but not image files
-        let block_size = 512;
+    /// Opens a block device or image file, see [disk_geometry] for image_block_size.
+    /// Image files in a virtual machine format are detected by their signature.
+    pub fn open<P: AsRef<Path>>(path: P, image_block_size: Option<usize>) -> Result<Self> {
+        Self::open_with(path.as_ref(), image_block_size, true)
+    }
+
+    /// Same as [DiskWrapper::open] without write access, to inspect a disk
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        image_block_size: Option<usize>,
+    ) -> Result<Self> {
+        Self::open_with(path.as_ref(), image_block_size, false)
+    }
+
+    fn open_with(path: &Path, image_block_size: Option<usize>, write: bool) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(write).open(path)?;
+        let (size, block_size) = disk_geometry(&mut file, image_block_size)?;
         let block = vec![0u8; block_size].into_boxed_slice();
+        let is_file = file.metadata()?.is_file();
+        let image = if is_file {
+            image::open(file.try_clone()?)?
+        } else {
+            None
+        };
+        let disk = image.unwrap_or_else(|| {
+            Box::new(RawDisk {
+                file,
+                size,
+                block_len: block_size,
+                sparse: is_file,
+            })
+        });
         Ok(Self {
             disk,
             size,
@@ -52,10 +208,9 @@ impl DiskWrapper {
 
         // Do aligned I/O quickly
         if self.seek % block_len == 0 && buf_len as u64 % block_len == 0 {
-            self.disk.seek(SeekFrom::Start(self.seek))?;
             match buf {
-                Buffer::Read(read) => self.disk.read_exact(read)?,
-                Buffer::Write(write) => self.disk.write_all(write)?,
+                Buffer::Read(read) => self.disk.read_at(self.seek, read)?,
+                Buffer::Write(write) => self.disk.write_at(self.seek, write)?,
             }
             self.seek = self.seek.checked_add(buf_len.try_into().unwrap()).unwrap();
             return Ok(buf_len);
@@ -68,9 +223,8 @@ impl DiskWrapper {
             let remaining = buf_len.checked_sub(i).unwrap();
             let len = cmp::min(remaining, self.block.len().checked_sub(offset).unwrap());
 
-            self.disk
-                .seek(SeekFrom::Start(block.checked_mul(block_len).unwrap()))?;
-            self.disk.read_exact(&mut self.block)?;
+            let block_offset = block.checked_mul(block_len).unwrap();
+            self.disk.read_at(block_offset, &mut self.block)?;
 
             match buf {
                 Buffer::Read(read) => {
@@ -81,9 +235,7 @@ impl DiskWrapper {
                     self.block[offset..offset.checked_add(len).unwrap()]
                         .copy_from_slice(&write[i..i.checked_add(len).unwrap()]);
 
-                    self.disk
-                        .seek(SeekFrom::Start(block.checked_mul(block_len).unwrap()))?;
-                    self.disk.write_all(&mut self.block)?;
+                    self.disk.write_at(block_offset, &self.block)?;
                 }
             }
 
@@ -0,0 +1,494 @@
+//! Hybrid ISO 9660 images with El Torito boot entries, wrapping a complete disk image.
+//!
+//! The disk image is stored as the file REDOX.IMG, aligned so that its partitions can
+//! also be listed in the partition table of the system area, in the first 32 KiB of
+//! the ISO. BIOS boots REDOX.IMG as an emulated hard disk and UEFI boots its EFI
+//! system partition, so the bootloaders find the same disk layout as when installed.
+
+use std::{fs::File, io, ops::Range, os::unix::fs::FileExt};
+
+use crate::reproducible::civil_time;
+
+pub const MAGIC: &[u8] = b"CD001";
+/// Reserved start of the ISO, ignored by ISO 9660
+pub const SYSTEM_AREA_SIZE: u64 = 16 * SECTOR_SIZE;
+/// Offset of the magic in the first volume descriptor, after the system area
+pub const MAGIC_OFFSET: u64 = SYSTEM_AREA_SIZE + 1;
+const SECTOR_SIZE: u64 = 2048;
+/// Offset of REDOX.IMG, leaving its partitions aligned to 1 MiB in the ISO too
+pub const DISK_OFFSET: u64 = 1 << 20;
+/// Space after REDOX.IMG, for the GPT mirror of the system area partition table
+const TAIL_SIZE: u64 = 64 * 1024;
+/// Largest extent of a file, files above it are split in several extents
+const MAX_EXTENT_SIZE: u64 = u32::MAX as u64 / SECTOR_SIZE * SECTOR_SIZE;
+
+const PVD_SECTOR: u64 = SYSTEM_AREA_SIZE / SECTOR_SIZE;
+const BOOT_RECORD_SECTOR: u64 = 17;
+const TERMINATOR_SECTOR: u64 = 18;
+const BOOT_CATALOG_SECTOR: u64 = 19;
+const L_PATH_TABLE_SECTOR: u64 = 20;
+const M_PATH_TABLE_SECTOR: u64 = 21;
+const ROOT_DIR_SECTOR: u64 = 22;
+
+const PLATFORM_X86: u8 = 0x00;
+const PLATFORM_EFI: u8 = 0xEF;
+const MEDIA_NO_EMULATION: u8 = 0;
+const MEDIA_HARD_DISK: u8 = 4;
+/// MBR partition type of the protective entry covering a GPT disk
+const GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// El Torito boot entries, pointing into the wrapped disk image
+#[derive(Clone, Debug, Default)]
+pub struct IsoBoot {
+    /// Boot the disk image as an emulated hard disk, running the BIOS bootloader in its MBR.
+    /// The disk image must have 512-byte blocks. The value is the MBR type of its first
+    /// partition, which describes the emulated disk; GPT disks only have a protective
+    /// entry in their MBR, so it is derived from their partitions.
+    pub bios: Option<u8>,
+    /// Byte range of the EFI system partition in the disk image, aligned to 2048 bytes
+    pub efi: Option<Range<u64>>,
+}
+
+/// Writes an ISO wrapping a disk image, returning its size. The system area is left
+/// zeroed for the caller to write the bootloader and the partition table to.
+pub fn write(file: &File, disk: &File, boot: &IsoBoot, time: u64) -> io::Result<u64> {
+    let disk_size = disk.metadata()?.len();
+    let iso_size = (DISK_OFFSET + disk_size).next_multiple_of(SECTOR_SIZE) + TAIL_SIZE;
+    let date = Date::new(time);
+
+    // Boot catalog, with the BIOS entry as default and the EFI entry in its own section
+    let disk_sector = DISK_OFFSET / SECTOR_SIZE;
+    let bios_entry = match boot.bios {
+        Some(system_type) => {
+            let mut mbr = [0; 512];
+            disk.read_exact_at(&mut mbr, 0)?;
+            if mbr[510..512] != [0x55, 0xAA] {
+                return Err(invalid_input("disk image has no MBR to boot from"));
+            }
+            if system_type == 0 || system_type == GPT_PROTECTIVE_TYPE {
+                return Err(invalid_input(
+                    "emulated hard disk needs the type of its first partition",
+                ));
+            }
+            Some(boot_entry(MEDIA_HARD_DISK, system_type, 1, disk_sector))
+        }
+        None => None,
+    };
+    let efi_entry = match &boot.efi {
+        Some(efi) => {
+            if efi.start % SECTOR_SIZE != 0 {
+                return Err(invalid_input("EFI partition is not aligned to 2048 bytes"));
+            }
+            // Firmware uses the rest of the ISO when the size does not fit
+            let sectors = u16::try_from((efi.end - efi.start) / 512).unwrap_or(0);
+            Some(boot_entry(
+                MEDIA_NO_EMULATION,
+                0,
+                sectors,
+                disk_sector + efi.start / SECTOR_SIZE,
+            ))
+        }
+        None => None,
+    };
+    let mut catalog = Vec::with_capacity(SECTOR_SIZE as usize);
+    match (bios_entry, efi_entry) {
+        (Some(bios_entry), Some(efi_entry)) => {
+            catalog.extend(validation_entry(PLATFORM_X86));
+            catalog.extend(bios_entry);
+            catalog.extend(section_header(PLATFORM_EFI, 1));
+            catalog.extend(efi_entry);
+        }
+        (Some(entry), None) => {
+            catalog.extend(validation_entry(PLATFORM_X86));
+            catalog.extend(entry);
+        }
+        (None, Some(entry)) => {
+            catalog.extend(validation_entry(PLATFORM_EFI));
+            catalog.extend(entry);
+        }
+        (None, None) => return Err(invalid_input("ISO image has no bootloader")),
+    }
+
+    // Root directory, holding REDOX.IMG in as many extents as needed
+    let mut root_dir = Vec::new();
+    let mut records = vec![
+        directory_record(&[0], ROOT_DIR_SECTOR as u32, 0, true, false, &date),
+        directory_record(&[1], ROOT_DIR_SECTOR as u32, 0, true, false, &date),
+    ];
+    let mut offset = 0;
+    loop {
+        let size = (disk_size - offset).min(MAX_EXTENT_SIZE);
+        let last = offset + size == disk_size;
+        records.push(directory_record(
+            b"REDOX.IMG;1",
+            ((DISK_OFFSET + offset) / SECTOR_SIZE) as u32,
+            size as u32,
+            false,
+            !last,
+            &date,
+        ));
+        offset += size;
+        if last {
+            break;
+        }
+    }
+    for record in records {
+        // Records do not cross sector boundaries
+        let used = root_dir.len() % SECTOR_SIZE as usize;
+        if used + record.len() > SECTOR_SIZE as usize {
+            root_dir.resize(root_dir.len() + SECTOR_SIZE as usize - used, 0);
+        }
+        root_dir.extend(record);
+    }
+    let root_dir_size = (root_dir.len() as u64).next_multiple_of(SECTOR_SIZE);
+    if ROOT_DIR_SECTOR * SECTOR_SIZE + root_dir_size > DISK_OFFSET {
+        return Err(invalid_input("disk image is too large for an ISO"));
+    }
+    // The size of the root directory is only known now
+    for dot in [0, 34] {
+        root_dir[dot + 10..dot + 18].copy_from_slice(&both_u32(root_dir_size as u32));
+    }
+
+    let mut l_path_table = vec![1, 0];
+    l_path_table.extend((ROOT_DIR_SECTOR as u32).to_le_bytes());
+    l_path_table.extend(1u16.to_le_bytes());
+    l_path_table.extend([0, 0]);
+    let mut m_path_table = vec![1, 0];
+    m_path_table.extend((ROOT_DIR_SECTOR as u32).to_be_bytes());
+    m_path_table.extend(1u16.to_be_bytes());
+    m_path_table.extend([0, 0]);
+
+    let mut pvd = volume_descriptor(1);
+    put_str(&mut pvd[8..40], "");
+    put_str(&mut pvd[40..72], "REDOX");
+    pvd[80..88].copy_from_slice(&both_u32((iso_size / SECTOR_SIZE) as u32));
+    pvd[120..124].copy_from_slice(&both_u16(1));
+    pvd[124..128].copy_from_slice(&both_u16(1));
+    pvd[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+    pvd[132..140].copy_from_slice(&both_u32(l_path_table.len() as u32));
+    pvd[140..144].copy_from_slice(&(L_PATH_TABLE_SECTOR as u32).to_le_bytes());
+    pvd[148..152].copy_from_slice(&(M_PATH_TABLE_SECTOR as u32).to_be_bytes());
+    pvd[156..190].copy_from_slice(&root_dir[..34]);
+    for (range, value) in [
+        (190..318, ""),
+        (318..446, ""),
+        (446..574, ""),
+        (574..702, "REDOX INSTALLER"),
+        (702..739, ""),
+        (739..776, ""),
+        (776..813, ""),
+    ] {
+        put_str(&mut pvd[range], value);
+    }
+    pvd[813..830].copy_from_slice(&date.volume);
+    pvd[830..847].copy_from_slice(&date.volume);
+    pvd[847..864].copy_from_slice(&Date::UNSET);
+    pvd[864..881].copy_from_slice(&Date::UNSET);
+    pvd[881] = 1;
+
+    let mut boot_record = volume_descriptor(0);
+    boot_record[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+    boot_record[71..75].copy_from_slice(&(BOOT_CATALOG_SECTOR as u32).to_le_bytes());
+
+    file.set_len(iso_size)?;
+    for (sector, data) in [
+        (PVD_SECTOR, &pvd),
+        (BOOT_RECORD_SECTOR, &boot_record),
+        (TERMINATOR_SECTOR, &volume_descriptor(255)),
+        (BOOT_CATALOG_SECTOR, &catalog),
+        (L_PATH_TABLE_SECTOR, &l_path_table),
+        (M_PATH_TABLE_SECTOR, &m_path_table),
+        (ROOT_DIR_SECTOR, &root_dir),
+    ] {
+        file.write_all_at(data, sector * SECTOR_SIZE)?;
+    }
+
+    // Copy the disk image, leaving ranges of zeros sparse
+    let mut buf = vec![0; 1 << 20];
+    let mut offset = 0;
+    while offset < disk_size {
+        let len = buf.len().min((disk_size - offset) as usize);
+        disk.read_exact_at(&mut buf[..len], offset)?;
+        if buf[..len].iter().any(|&b| b != 0) {
+            file.write_all_at(&buf[..len], DISK_OFFSET + offset)?;
+        }
+        offset += len as u64;
+    }
+
+    Ok(iso_size)
+}
+
+/// Dates of the volume descriptor and of directory records
+struct Date {
+    volume: [u8; 17],
+    record: [u8; 7],
+}
+
+impl Date {
+    /// Dates that are not specified
+    const UNSET: [u8; 17] = *b"0000000000000000\0";
+
+    fn new(time: u64) -> Self {
+        let [year, month, day, hour, min, sec] = civil_time(time);
+        let mut volume = [0; 17];
+        volume[..16].copy_from_slice(
+            format!("{year:04}{month:02}{day:02}{hour:02}{min:02}{sec:02}00").as_bytes(),
+        );
+        // Directory records count years from 1900 in a byte
+        let record = [
+            (year - 1900).min(255) as u8,
+            month as u8,
+            day as u8,
+            hour as u8,
+            min as u8,
+            sec as u8,
+            0,
+        ];
+        Self { volume, record }
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Encodes a value in both byte orders, as ISO 9660 does for most fields
+fn both_u16(value: u16) -> [u8; 4] {
+    let mut both = [0; 4];
+    both[..2].copy_from_slice(&value.to_le_bytes());
+    both[2..].copy_from_slice(&value.to_be_bytes());
+    both
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let mut both = [0; 8];
+    both[..4].copy_from_slice(&value.to_le_bytes());
+    both[4..].copy_from_slice(&value.to_be_bytes());
+    both
+}
+
+/// Writes a string padded with spaces
+fn put_str(field: &mut [u8], value: &str) {
+    field.fill(b' ');
+    field[..value.len()].copy_from_slice(value.as_bytes());
+}
+
+fn volume_descriptor(kind: u8) -> Vec<u8> {
+    let mut descriptor = vec![0; SECTOR_SIZE as usize];
+    descriptor[0] = kind;
+    descriptor[1..6].copy_from_slice(MAGIC);
+    descriptor[6] = 1;
+    descriptor
+}
+
+fn directory_record(
+    name: &[u8],
+    extent: u32,
+    size: u32,
+    directory: bool,
+    multi_extent: bool,
+    date: &Date,
+) -> Vec<u8> {
+    let len = (33 + name.len()).next_multiple_of(2);
+    let mut record = vec![0; len];
+    record[0] = len as u8;
+    record[2..10].copy_from_slice(&both_u32(extent));
+    record[10..18].copy_from_slice(&both_u32(size));
+    record[18..25].copy_from_slice(&date.record);
+    record[25] = u8::from(directory) << 1 | u8::from(multi_extent) << 7;
+    record[28..32].copy_from_slice(&both_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+fn validation_entry(platform: u8) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[0] = 1;
+    entry[1] = platform;
+    entry[4..9].copy_from_slice(b"REDOX");
+    entry[30..32].copy_from_slice(&[0x55, 0xAA]);
+    // The 16-bit words of the entry sum to zero
+    let sum = entry.chunks_exact(2).fold(0u16, |sum, word| {
+        sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+    });
+    entry[28..30].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+    entry
+}
+
+fn section_header(platform: u8, entries: u16) -> [u8; 32] {
+    let mut header = [0; 32];
+    // Final section header
+    header[0] = 0x91;
+    header[1] = platform;
+    header[2..4].copy_from_slice(&entries.to_le_bytes());
+    header
+}
+
+/// Bootable entry loading an image at the default segment 0x7C0,
+/// with its size in 512-byte sectors and its location in ISO sectors
+fn boot_entry(media: u8, system_type: u8, sectors: u16, sector: u64) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[0] = 0x88;
+    entry[1] = media;
+    entry[4] = system_type;
+    entry[6..8].copy_from_slice(&sectors.to_le_bytes());
+    entry[8..12].copy_from_slice(&(sector as u32).to_le_bytes());
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn sector(iso: &[u8], sector: u64) -> &[u8] {
+        &iso[(sector * SECTOR_SIZE) as usize..((sector + 1) * SECTOR_SIZE) as usize]
+    }
+
+    /// Writes an ISO from a disk image with an MBR, returning the ISO and the disk image
+    fn write_iso(name: &str, boot: &IsoBoot) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let dir = env::temp_dir().join(format!("redox_installer_{name}_{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let disk_path = dir.join("disk.img");
+        let iso_path = dir.join("disk.iso");
+        let mut disk: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        fs::write(&disk_path, &disk)?;
+
+        let res = write(
+            &File::create(&iso_path)?,
+            &File::open(&disk_path)?,
+            boot,
+            // 2024-02-29 12:34:56 UTC
+            1_709_210_096,
+        );
+        let iso = fs::read(&iso_path);
+        fs::remove_dir_all(&dir)?;
+        let iso_size = res?;
+        let iso = iso?;
+        assert_eq!(iso.len() as u64, iso_size);
+        Ok((iso, disk))
+    }
+
+    #[test]
+    fn volume_descriptors_and_boot_catalog() {
+        let efi = 0x10_0000..0x20_0000;
+        let (iso, disk) = write_iso(
+            "hybrid_iso",
+            &IsoBoot {
+                bios: Some(0xEF),
+                efi: Some(efi.clone()),
+            },
+        )
+        .unwrap();
+
+        // Primary volume descriptor
+        let pvd = sector(&iso, PVD_SECTOR);
+        assert_eq!(pvd[0], 1);
+        assert_eq!(&pvd[1..6], MAGIC);
+        assert_eq!(pvd[6], 1);
+        assert_eq!(
+            pvd[80..88],
+            both_u32((iso.len() as u64 / SECTOR_SIZE) as u32)
+        );
+        assert_eq!(pvd[128..132], both_u16(SECTOR_SIZE as u16));
+        assert_eq!(&pvd[813..829], b"2024022912345600");
+        // Root directory record
+        assert_eq!(pvd[156], 34);
+        assert_eq!(pvd[158..166], both_u32(ROOT_DIR_SECTOR as u32));
+        assert_eq!(pvd[156 + 25], 2);
+
+        // El Torito boot record, then the terminator
+        let boot_record = sector(&iso, BOOT_RECORD_SECTOR);
+        assert_eq!(boot_record[0], 0);
+        assert_eq!(&boot_record[1..6], MAGIC);
+        assert_eq!(&boot_record[7..30], b"EL TORITO SPECIFICATION");
+        assert_eq!(
+            boot_record[71..75],
+            (BOOT_CATALOG_SECTOR as u32).to_le_bytes()
+        );
+        let terminator = sector(&iso, TERMINATOR_SECTOR);
+        assert_eq!(terminator[0], 255);
+        assert_eq!(&terminator[1..6], MAGIC);
+
+        // Validation entry, whose words sum to zero
+        let catalog = sector(&iso, BOOT_CATALOG_SECTOR);
+        let validation = &catalog[0..32];
+        assert_eq!(validation[0], 1);
+        assert_eq!(validation[1], PLATFORM_X86);
+        assert_eq!(validation[30..32], [0x55, 0xAA]);
+        let sum = validation.chunks_exact(2).fold(0u16, |sum, word| {
+            sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+        });
+        assert_eq!(sum, 0);
+
+        // Default BIOS entry, emulating a hard disk from the MBR of REDOX.IMG
+        let disk_sector = (DISK_OFFSET / SECTOR_SIZE) as u32;
+        let bios = &catalog[32..64];
+        assert_eq!(bios[0], 0x88);
+        assert_eq!(bios[1], MEDIA_HARD_DISK);
+        assert_eq!(bios[4], 0xEF);
+        assert_eq!(bios[6..8], 1u16.to_le_bytes());
+        assert_eq!(bios[8..12], disk_sector.to_le_bytes());
+
+        // Final section with the EFI entry, booting the EFI system partition
+        let section = &catalog[64..96];
+        assert_eq!(section[0], 0x91);
+        assert_eq!(section[1], PLATFORM_EFI);
+        assert_eq!(section[2..4], 1u16.to_le_bytes());
+        let efi_entry = &catalog[96..128];
+        assert_eq!(efi_entry[0], 0x88);
+        assert_eq!(efi_entry[1], MEDIA_NO_EMULATION);
+        assert_eq!(
+            efi_entry[6..8],
+            (((efi.end - efi.start) / 512) as u16).to_le_bytes()
+        );
+        assert_eq!(
+            efi_entry[8..12],
+            (disk_sector + (efi.start / SECTOR_SIZE) as u32).to_le_bytes()
+        );
+        assert!(catalog[128..].iter().all(|&b| b == 0));
+
+        // REDOX.IMG after the dot entries of the root directory, holding the disk image
+        let root_dir = sector(&iso, ROOT_DIR_SECTOR);
+        let record = &root_dir[68..];
+        assert_eq!(record[32], 11);
+        assert_eq!(&record[33..44], b"REDOX.IMG;1");
+        assert_eq!(record[2..10], both_u32(disk_sector));
+        assert_eq!(record[10..18], both_u32(disk.len() as u32));
+        assert_eq!(record[25], 0);
+        let start = DISK_OFFSET as usize;
+        assert!(iso[start..start + disk.len()] == disk[..]);
+    }
+
+    #[test]
+    fn efi_only_catalog() {
+        let (iso, _) = write_iso(
+            "efi_iso",
+            &IsoBoot {
+                bios: None,
+                efi: Some(0x10_0000..0x20_0000),
+            },
+        )
+        .unwrap();
+        let catalog = sector(&iso, BOOT_CATALOG_SECTOR);
+        assert_eq!(catalog[1], PLATFORM_EFI);
+        assert_eq!(catalog[32], 0x88);
+        assert_eq!(catalog[33], MEDIA_NO_EMULATION);
+        assert!(catalog[64..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn protective_type_is_refused() {
+        let err = write_iso(
+            "protective_iso",
+            &IsoBoot {
+                bios: Some(GPT_PROTECTIVE_TYPE),
+                efi: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,210 @@
+//! Virtual machine image formats, read and written through [crate::disk_wrapper::DiskWrapper]
+
+pub mod iso;
+mod qcow2;
+mod vdi;
+mod vmdk;
+
+use std::{fmt, fs::File, io, os::unix::fs::FileExt, path::Path};
+
+use crate::config::general::ImageFormat;
+
+/// Block I/O of the disk contained in an image file
+pub trait VirtualDisk: fmt::Debug + Send {
+    /// Size of the contained disk in bytes
+    fn size(&self) -> u64;
+
+    /// Reads at a disk offset, ranges never written read as zeros
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Writes at a disk offset, allocating image storage as needed
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Returns the format of an image file by its signature
+pub fn detect(file: &File) -> io::Result<ImageFormat> {
+    // Raw images may be shorter than the header of other formats
+    let mut header = vec![0; iso::MAGIC_OFFSET as usize + iso::MAGIC.len()];
+    let mut len = 0;
+    while len < header.len() {
+        match file.read_at(&mut header[len..], len as u64)? {
+            0 => break,
+            read => len += read,
+        }
+    }
+
+    Ok(if header.starts_with(qcow2::MAGIC) {
+        ImageFormat::Qcow2
+    } else if header.starts_with(vmdk::MAGIC) {
+        ImageFormat::Vmdk
+    } else if header[0x40..0x44] == vdi::SIGNATURE.to_le_bytes() {
+        ImageFormat::Vdi
+    } else if header[iso::MAGIC_OFFSET as usize..].starts_with(iso::MAGIC) {
+        ImageFormat::Iso
+    } else {
+        ImageFormat::Raw
+    })
+}
+
+/// Opens an image file, returning None for raw images and ISO images, which are read as raw
+pub fn open(file: File) -> io::Result<Option<Box<dyn VirtualDisk>>> {
+    Ok(match detect(&file)? {
+        ImageFormat::Raw | ImageFormat::Iso => None,
+        ImageFormat::Qcow2 => Some(Box::new(qcow2::Qcow2::open(file)?)),
+        ImageFormat::Vmdk => Some(Box::new(vmdk::Vmdk::open(file)?)),
+        ImageFormat::Vdi => Some(Box::new(vdi::Vdi::open(file)?)),
+    })
+}
+
+/// Creates an empty image file containing a disk of the given size.
+/// The GUID identifies the image in formats that have one.
+pub fn create(path: &Path, format: ImageFormat, size: u64, guid: uuid::Uuid) -> io::Result<()> {
+    let file = File::create(path)?;
+    match format {
+        ImageFormat::Raw => file.set_len(size),
+        ImageFormat::Qcow2 => qcow2::create(&file, size),
+        ImageFormat::Vmdk => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            vmdk::create(&file, &name, size, guid)
+        }
+        ImageFormat::Vdi => vdi::create(&file, size, guid),
+        ImageFormat::Iso => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ISO images are written by iso::write from a raw disk image",
+        )),
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Checks that an access is within the contained disk
+fn check_range(disk_size: u64, offset: u64, len: usize) -> io::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= disk_size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("access at {offset:#x} beyond the disk size {disk_size:#x}"),
+        )),
+    }
+}
+
+/// Splits an access into pieces that do not cross units of unit_size bytes,
+/// calling f with the unit index, the offset in the unit and the range of the buffer
+fn for_each_unit(
+    offset: u64,
+    len: usize,
+    unit_size: u64,
+    mut f: impl FnMut(u64, u64, std::ops::Range<usize>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut i = 0;
+    while i < len {
+        let position = offset + i as u64;
+        let unit_offset = position % unit_size;
+        let piece = ((unit_size - unit_offset) as usize).min(len - i);
+        f(position / unit_size, unit_offset, i..i + piece)?;
+        i += piece;
+    }
+    Ok(())
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64_le(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    /// Disk crossing a VMDK grain table, not a multiple of clusters and blocks
+    const SIZE: u64 = 40 * 1024 * 1024 + 512;
+
+    /// Writes crossing clusters, grains and blocks, and zeros over both unwritten and written data
+    fn writes() -> Vec<(u64, Vec<u8>)> {
+        let data = |len: usize, seed: usize| -> Vec<u8> {
+            (0..len).map(|i| (i * 7 + seed) as u8 | 1).collect()
+        };
+        vec![
+            (0, data(100, 1)),
+            (0xFFF0, data(0x20, 2)),
+            (0xF_FFFF, data(3, 3)),
+            (32 * 1024 * 1024 - 10, data(20, 4)),
+            (0x30_0000, data(200 * 1024, 5)),
+            (0x50_0000, vec![0; 0x1_0000]),
+            (0x31_0000, vec![0; 0x1_8001]),
+            (40, vec![0; 20]),
+            (SIZE - 7, data(7, 6)),
+        ]
+    }
+
+    fn open_rw(path: &Path) -> Box<dyn VirtualDisk> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        open(file).unwrap().expect("image opened as raw")
+    }
+
+    /// Writes the same data to an image and a raw image, then reads the reopened image back
+    fn round_trip(format: ImageFormat) {
+        let dir = env::temp_dir().join(format!("redox_installer_{format}_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join(format!("disk.{format}"));
+        let raw_path = dir.join("disk.raw");
+        create(&image_path, format, SIZE, uuid::Uuid::nil()).unwrap();
+        create(&raw_path, ImageFormat::Raw, SIZE, uuid::Uuid::nil()).unwrap();
+        assert_eq!(detect(&File::open(&image_path).unwrap()).unwrap(), format);
+
+        let raw = fs::OpenOptions::new().write(true).open(&raw_path).unwrap();
+        let mut image = open_rw(&image_path);
+        assert_eq!(image.size(), SIZE);
+        for (offset, data) in writes() {
+            image.write_at(offset, &data).unwrap();
+            raw.write_all_at(&data, offset).unwrap();
+        }
+        image.flush().unwrap();
+        drop(image);
+
+        let raw = File::open(&raw_path).unwrap();
+        let mut image = open_rw(&image_path);
+        assert!(image.read_at(SIZE - 1, &mut [0; 2]).is_err());
+        let chunk = 0x10_0000;
+        let mut expected = vec![0; chunk];
+        let mut found = vec![0; chunk];
+        for offset in (0..SIZE).step_by(chunk) {
+            let len = chunk.min((SIZE - offset) as usize);
+            raw.read_exact_at(&mut expected[..len], offset).unwrap();
+            image.read_at(offset, &mut found[..len]).unwrap();
+            assert!(
+                expected[..len] == found[..len],
+                "{format} differs from raw at {offset:#x}"
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn qcow2_round_trip() {
+        round_trip(ImageFormat::Qcow2);
+    }
+
+    #[test]
+    fn vmdk_round_trip() {
+        round_trip(ImageFormat::Vmdk);
+    }
+
+    #[test]
+    fn vdi_round_trip() {
+        round_trip(ImageFormat::Vdi);
+    }
+}
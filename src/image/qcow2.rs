@@ -0,0 +1,355 @@
+//! QEMU copy-on-write images, version 3 without backing files or snapshots
+
+use std::{collections::BTreeMap, fs::File, io, os::unix::fs::FileExt};
+
+use super::{check_range, for_each_unit, invalid_data, VirtualDisk};
+
+pub const MAGIC: &[u8] = b"QFI\xfb";
+const VERSION: u32 = 3;
+const HEADER_LENGTH: usize = 104;
+/// 64 KiB clusters, the default of qemu-img
+const CLUSTER_BITS: u32 = 16;
+/// Refcounts are 16 bits wide, the only width written by this module
+const REFCOUNT_ORDER: u32 = 4;
+
+/// L1 and L2 entry flag: the refcount of the cluster is 1, so it is written in place
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// L2 entry flag: the cluster reads as zeros
+const OFLAG_ZERO: u64 = 1;
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Writes the header, L1 table, refcount table and first refcount block of an empty image
+pub fn create(file: &File, size: u64) -> io::Result<()> {
+    let cluster_size = 1u64 << CLUSTER_BITS;
+    let l2_entries = cluster_size / 8;
+    let refcount_block_entries = (cluster_size * 8) >> REFCOUNT_ORDER;
+
+    let l1_size = size.div_ceil(cluster_size * l2_entries);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+    // The refcount table is not grown, so it covers a fully written image
+    let max_clusters = 1 + l1_clusters + l1_size + size.div_ceil(cluster_size);
+    let max_clusters = max_clusters + max_clusters / refcount_block_entries + 16;
+    let refcount_table_clusters = (max_clusters.div_ceil(refcount_block_entries) * 8)
+        .div_ceil(cluster_size)
+        .max(1);
+
+    let l1_table_offset = cluster_size;
+    let refcount_table_offset = l1_table_offset + l1_clusters * cluster_size;
+    let refcount_block_offset = refcount_table_offset + refcount_table_clusters * cluster_size;
+    let clusters = refcount_block_offset / cluster_size + 1;
+    if clusters > refcount_block_entries {
+        return Err(invalid_data(format!("disk size {size:#x} is too large")));
+    }
+
+    let mut header = vec![0; HEADER_LENGTH];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&VERSION.to_be_bytes());
+    header[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&size.to_be_bytes());
+    header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+    header[40..48].copy_from_slice(&l1_table_offset.to_be_bytes());
+    header[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+    header[56..60].copy_from_slice(&(refcount_table_clusters as u32).to_be_bytes());
+    header[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+    header[100..104].copy_from_slice(&(HEADER_LENGTH as u32).to_be_bytes());
+
+    file.set_len(clusters * cluster_size)?;
+    file.write_all_at(&header, 0)?;
+    file.write_all_at(&refcount_block_offset.to_be_bytes(), refcount_table_offset)?;
+    // Every cluster written so far is used once
+    let refcounts: Vec<u8> = (0..clusters).flat_map(|_| 1u16.to_be_bytes()).collect();
+    file.write_all_at(&refcounts, refcount_block_offset)
+}
+
+#[derive(Debug)]
+pub struct Qcow2 {
+    file: File,
+    size: u64,
+    cluster_bits: u32,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    /// L2 tables read so far, by L1 index
+    l2_tables: BTreeMap<usize, Vec<u64>>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// Index of the next cluster appended to the file
+    next_cluster: u64,
+}
+
+impl Qcow2 {
+    pub fn open(file: File) -> io::Result<Self> {
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact_at(&mut header[..72], 0)?;
+        let version = read_u32(&header, 4);
+        match version {
+            2 => (),
+            3 => {
+                file.read_exact_at(&mut header[72..], 72)?;
+                if read_u64(&header, 72) != 0 {
+                    return Err(invalid_data(
+                        "qcow2 incompatible features are not supported",
+                    ));
+                }
+                if read_u32(&header, 96) != REFCOUNT_ORDER {
+                    return Err(invalid_data("qcow2 refcounts must be 16 bits wide"));
+                }
+            }
+            _ => {
+                return Err(invalid_data(format!(
+                    "qcow2 version {version} is not supported"
+                )))
+            }
+        }
+        if read_u64(&header, 8) != 0 {
+            return Err(invalid_data("qcow2 backing files are not supported"));
+        }
+        if read_u32(&header, 32) != 0 {
+            return Err(invalid_data("encrypted qcow2 images are not supported"));
+        }
+        // Clusters shared with snapshots cannot be written in place
+        if read_u32(&header, 60) != 0 {
+            return Err(invalid_data("qcow2 snapshots are not supported"));
+        }
+
+        let cluster_bits = read_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid_data(format!(
+                "invalid qcow2 cluster bits {cluster_bits}"
+            )));
+        }
+        let size = read_u64(&header, 24);
+        let l1_size = read_u32(&header, 36) as usize;
+        let l1_table_offset = read_u64(&header, 40);
+        let refcount_table_offset = read_u64(&header, 48);
+        let refcount_table_size = (read_u32(&header, 56) as usize) << (cluster_bits - 3);
+
+        let read_table = |offset: u64, entries: usize| -> io::Result<Vec<u64>> {
+            let mut data = vec![0; entries * 8];
+            file.read_exact_at(&mut data, offset)?;
+            Ok(data
+                .chunks_exact(8)
+                .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+                .collect())
+        };
+        let l1_table = read_table(l1_table_offset, l1_size)?;
+        let refcount_table = read_table(refcount_table_offset, refcount_table_size)?;
+        let next_cluster = file.metadata()?.len().div_ceil(1 << cluster_bits);
+
+        let qcow2 = Self {
+            file,
+            size,
+            cluster_bits,
+            l1_table_offset,
+            l1_table,
+            l2_tables: BTreeMap::new(),
+            refcount_table_offset,
+            refcount_table,
+            next_cluster,
+        };
+        if size.div_ceil(qcow2.cluster_size() * qcow2.l2_entries()) > l1_size as u64 {
+            return Err(invalid_data("qcow2 L1 table is smaller than the disk"));
+        }
+        Ok(qcow2)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Appends a zeroed cluster to the file, returning its offset
+    fn allocate(&mut self) -> io::Result<u64> {
+        let cluster = self.next_cluster;
+        self.next_cluster += 1;
+        self.file.set_len(self.next_cluster << self.cluster_bits)?;
+        self.set_refcount(cluster)?;
+        Ok(cluster << self.cluster_bits)
+    }
+
+    /// Marks a cluster as used once, allocating its refcount block if needed
+    fn set_refcount(&mut self, cluster: u64) -> io::Result<()> {
+        let block_entries = (self.cluster_size() * 8) >> REFCOUNT_ORDER;
+        let index = (cluster / block_entries) as usize;
+        let Some(&block) = self.refcount_table.get(index) else {
+            return Err(io::Error::other("qcow2 refcount table is full"));
+        };
+        let block = if block == 0 {
+            let block = self.next_cluster << self.cluster_bits;
+            self.next_cluster += 1;
+            self.file.set_len(self.next_cluster << self.cluster_bits)?;
+            self.refcount_table[index] = block;
+            self.file.write_all_at(
+                &block.to_be_bytes(),
+                self.refcount_table_offset + index as u64 * 8,
+            )?;
+            // The new block is counted by itself or by the next one
+            self.set_refcount(block >> self.cluster_bits)?;
+            block
+        } else {
+            block
+        };
+        self.file
+            .write_all_at(&1u16.to_be_bytes(), block + cluster % block_entries * 2)
+    }
+
+    /// Reads the L2 table of an L1 index, returning false if it is not allocated
+    fn load_l2(&mut self, l1_index: usize) -> io::Result<bool> {
+        if self.l2_tables.contains_key(&l1_index) {
+            return Ok(true);
+        }
+        let offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if offset == 0 {
+            return Ok(false);
+        }
+        let mut data = vec![0; self.cluster_size() as usize];
+        self.file.read_exact_at(&mut data, offset)?;
+        let table = data
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+            .collect();
+        self.l2_tables.insert(l1_index, table);
+        Ok(true)
+    }
+
+    fn l2_entry(&mut self, cluster: u64) -> io::Result<u64> {
+        let l1_index = (cluster / self.l2_entries()) as usize;
+        if !self.load_l2(l1_index)? {
+            return Ok(0);
+        }
+        let entry = self.l2_tables[&l1_index][(cluster % self.l2_entries()) as usize];
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(invalid_data("compressed qcow2 clusters are not supported"));
+        }
+        Ok(entry)
+    }
+
+    fn set_l2_entry(&mut self, cluster: u64, entry: u64) -> io::Result<()> {
+        let l1_index = (cluster / self.l2_entries()) as usize;
+        let l2_index = (cluster % self.l2_entries()) as usize;
+        if !self.load_l2(l1_index)? {
+            let offset = self.allocate()?;
+            self.l1_table[l1_index] = offset | OFLAG_COPIED;
+            self.file.write_all_at(
+                &self.l1_table[l1_index].to_be_bytes(),
+                self.l1_table_offset + l1_index as u64 * 8,
+            )?;
+            self.l2_tables
+                .insert(l1_index, vec![0; self.l2_entries() as usize]);
+        }
+        self.l2_tables.get_mut(&l1_index).unwrap()[l2_index] = entry;
+        let l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+    }
+}
+
+impl VirtualDisk for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        for_each_unit(
+            offset,
+            buf.len(),
+            self.cluster_size(),
+            |cluster, in_cluster, range| {
+                let entry = self.l2_entry(cluster)?;
+                let host = entry & OFFSET_MASK;
+                if host == 0 || entry & OFLAG_ZERO != 0 {
+                    buf[range].fill(0);
+                    Ok(())
+                } else {
+                    self.file.read_exact_at(&mut buf[range], host + in_cluster)
+                }
+            },
+        )
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        for_each_unit(
+            offset,
+            buf.len(),
+            self.cluster_size(),
+            |cluster, in_cluster, range| {
+                let entry = self.l2_entry(cluster)?;
+                let host = entry & OFFSET_MASK;
+                if host != 0 && entry & OFLAG_ZERO == 0 {
+                    return self.file.write_all_at(&buf[range], host + in_cluster);
+                }
+                // Unallocated and zero clusters already read as zeros
+                if buf[range.clone()].iter().all(|&b| b == 0) {
+                    return Ok(());
+                }
+                let host = if host == 0 {
+                    self.allocate()?
+                } else {
+                    // Zero clusters may keep their cluster, reuse it after clearing stale data
+                    self.file
+                        .write_all_at(&vec![0; self.cluster_size() as usize], host)?;
+                    host
+                };
+                self.file.write_all_at(&buf[range], host + in_cluster)?;
+                self.set_l2_entry(cluster, host | OFLAG_COPIED)
+            },
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn zero_cluster_is_reused() {
+        let path = env::temp_dir().join(format!("redox_installer_zero_{}.qcow2", process::id()));
+        create(&File::create(&path).unwrap(), 0x10_0000).unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut qcow2 = Qcow2::open(file).unwrap();
+
+        qcow2.write_at(0, &[0xAA; 0x200]).unwrap();
+        let len = qcow2.file.metadata().unwrap().len();
+        // As qemu-img marks a written cluster when zeroing it
+        let host = qcow2.l2_entry(0).unwrap() & OFFSET_MASK;
+        qcow2
+            .set_l2_entry(0, host | OFLAG_COPIED | OFLAG_ZERO)
+            .unwrap();
+        let mut buf = [0xFF; 0x400];
+        qcow2.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 0x400]);
+
+        qcow2.write_at(0x100, &[0x55; 0x10]).unwrap();
+        assert_eq!(qcow2.file.metadata().unwrap().len(), len);
+        assert_eq!(qcow2.l2_entry(0).unwrap(), host | OFLAG_COPIED);
+        qcow2.read_at(0, &mut buf).unwrap();
+        assert!(buf[..0x100].iter().all(|&b| b == 0));
+        assert_eq!(buf[0x100..0x110], [0x55; 0x10]);
+        assert!(buf[0x110..].iter().all(|&b| b == 0));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! VirtualBox disk images, dynamic or fixed, without differencing images
+
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use super::{check_range, for_each_unit, invalid_data, read_u32_le, read_u64_le, VirtualDisk};
+
+const PRE_HEADER: &[u8] = b"<<< Oracle VM VirtualBox Disk Image >>>\n";
+pub const SIGNATURE: u32 = 0xBEDA_107F;
+const VERSION: u32 = 0x0001_0001;
+const HEADER_SIZE: u32 = 400;
+const TYPE_DYNAMIC: u32 = 1;
+const TYPE_FIXED: u32 = 2;
+/// 1 MiB blocks, the only size VirtualBox creates
+const BLOCK_SIZE: u64 = 1 << 20;
+const BLOCK_FREE: u32 = u32::MAX;
+const BLOCK_ZERO: u32 = u32::MAX - 1;
+const BLOCKS_OFFSET: u64 = 0x200;
+/// Offset of the allocated block count in the header
+const BLOCKS_ALLOCATED_OFFSET: u64 = 0x184;
+
+/// Writes the header and an empty block map of a dynamic image
+pub fn create(file: &File, size: u64, guid: uuid::Uuid) -> io::Result<()> {
+    let blocks = size.div_ceil(BLOCK_SIZE);
+    if blocks >= BLOCK_ZERO as u64 {
+        return Err(invalid_data(format!("disk size {size:#x} is too large")));
+    }
+    // Blocks are aligned like VirtualBox does
+    let data_offset = (BLOCKS_OFFSET + blocks * 4).next_multiple_of(BLOCK_SIZE);
+
+    let mut header = vec![0; BLOCKS_OFFSET as usize];
+    header[..PRE_HEADER.len()].copy_from_slice(PRE_HEADER);
+    let mut put_u32 = |offset: usize, value: u32| {
+        header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    put_u32(0x40, SIGNATURE);
+    put_u32(0x44, VERSION);
+    put_u32(0x48, HEADER_SIZE);
+    put_u32(0x4C, TYPE_DYNAMIC);
+    put_u32(0x154, BLOCKS_OFFSET as u32);
+    put_u32(0x158, data_offset as u32);
+    // Legacy geometry is left for VirtualBox to compute, except the sector size
+    put_u32(0x168, 512);
+    put_u32(0x178, BLOCK_SIZE as u32);
+    put_u32(0x180, blocks as u32);
+    header[0x170..0x178].copy_from_slice(&size.to_le_bytes());
+    // Creation and modification UUIDs
+    header[0x188..0x198].copy_from_slice(&guid.to_bytes_le());
+    header[0x198..0x1A8].copy_from_slice(&guid.to_bytes_le());
+
+    file.set_len(data_offset)?;
+    file.write_all_at(&header, 0)?;
+    let block_map: Vec<u8> = (0..blocks).flat_map(|_| BLOCK_FREE.to_le_bytes()).collect();
+    file.write_all_at(&block_map, BLOCKS_OFFSET)
+}
+
+#[derive(Debug)]
+pub struct Vdi {
+    file: File,
+    size: u64,
+    block_size: u64,
+    blocks_offset: u64,
+    data_offset: u64,
+    /// Index of each disk block in the data area
+    blocks: Vec<u32>,
+    blocks_allocated: u32,
+}
+
+impl Vdi {
+    pub fn open(file: File) -> io::Result<Self> {
+        let mut header = [0; BLOCKS_OFFSET as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if read_u32_le(&header, 0x44) != VERSION {
+            return Err(invalid_data(format!(
+                "VDI version {:#x} is not supported",
+                read_u32_le(&header, 0x44)
+            )));
+        }
+        let image_type = read_u32_le(&header, 0x4C);
+        if image_type != TYPE_DYNAMIC && image_type != TYPE_FIXED {
+            return Err(invalid_data(format!(
+                "VDI image type {image_type} is not supported"
+            )));
+        }
+        if read_u32_le(&header, 0x17C) != 0 {
+            return Err(invalid_data("VDI block extra data is not supported"));
+        }
+
+        let blocks_offset = read_u32_le(&header, 0x154) as u64;
+        let data_offset = read_u32_le(&header, 0x158) as u64;
+        let size = read_u64_le(&header, 0x170);
+        let block_size = read_u32_le(&header, 0x178) as u64;
+        let block_count = read_u32_le(&header, 0x180) as usize;
+        let blocks_allocated = read_u32_le(&header, 0x184);
+        if block_size == 0 || size > block_count as u64 * block_size {
+            return Err(invalid_data("VDI block map is smaller than the disk"));
+        }
+
+        let mut block_map = vec![0; block_count * 4];
+        file.read_exact_at(&mut block_map, blocks_offset)?;
+        let blocks = block_map
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            file,
+            size,
+            block_size,
+            blocks_offset,
+            data_offset,
+            blocks,
+            blocks_allocated,
+        })
+    }
+
+    fn block_offset(&self, index: u32) -> u64 {
+        self.data_offset + index as u64 * self.block_size
+    }
+}
+
+impl VirtualDisk for Vdi {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        for_each_unit(
+            offset,
+            buf.len(),
+            self.block_size,
+            |block, in_block, range| match self.blocks[block as usize] {
+                BLOCK_FREE | BLOCK_ZERO => {
+                    buf[range].fill(0);
+                    Ok(())
+                }
+                index => self
+                    .file
+                    .read_exact_at(&mut buf[range], self.block_offset(index) + in_block),
+            },
+        )
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        for_each_unit(
+            offset,
+            buf.len(),
+            self.block_size,
+            |block, in_block, range| {
+                let index = match self.blocks[block as usize] {
+                    BLOCK_FREE | BLOCK_ZERO => {
+                        // Unallocated blocks already read as zeros
+                        if buf[range.clone()].iter().all(|&b| b == 0) {
+                            return Ok(());
+                        }
+                        let index = self.blocks_allocated;
+                        self.blocks_allocated += 1;
+                        self.file
+                            .set_len(self.block_offset(self.blocks_allocated))?;
+                        self.file
+                            .write_all_at(&buf[range], self.block_offset(index) + in_block)?;
+                        self.blocks[block as usize] = index;
+                        self.file
+                            .write_all_at(&index.to_le_bytes(), self.blocks_offset + block * 4)?;
+                        return self.file.write_all_at(
+                            &self.blocks_allocated.to_le_bytes(),
+                            BLOCKS_ALLOCATED_OFFSET,
+                        );
+                    }
+                    index => index,
+                };
+                self.file
+                    .write_all_at(&buf[range], self.block_offset(index) + in_block)
+            },
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.file)
+    }
+}
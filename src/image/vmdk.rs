@@ -0,0 +1,272 @@
+//! VMware monolithic sparse images, the hosted sparse extent format with an embedded descriptor
+
+use std::{collections::BTreeMap, fs::File, io, os::unix::fs::FileExt};
+
+use super::{check_range, for_each_unit, invalid_data, read_u32_le, read_u64_le, VirtualDisk};
+
+pub const MAGIC: &[u8] = b"KDMV";
+const VERSION: u32 = 1;
+/// Valid newline detection characters
+const FLAG_NEWLINE_TEST: u32 = 1 << 0;
+const FLAG_REDUNDANT_GRAIN_TABLE: u32 = 1 << 1;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const SECTOR_SIZE: u64 = 512;
+/// 64 KiB grains, the default of VMware and qemu-img
+const GRAIN_SECTORS: u64 = 128;
+const GTES_PER_GT: u64 = 512;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+
+fn descriptor(name: &str, capacity: u64, guid: uuid::Uuid) -> String {
+    // IDE geometry, as the adapter type
+    let cylinders = (capacity / (16 * 63)).min(16383);
+    format!(
+        r#"# Disk DescriptorFile
+version=1
+CID={cid:08x}
+parentCID=ffffffff
+createType="monolithicSparse"
+
+# Extent description
+RW {capacity} SPARSE "{name}"
+
+# The Disk Data Base
+#DDB
+
+ddb.virtualHWVersion = "4"
+ddb.geometry.cylinders = "{cylinders}"
+ddb.geometry.heads = "16"
+ddb.geometry.sectors = "63"
+ddb.adapterType = "ide"
+ddb.uuid.image = "{guid}"
+"#,
+        cid = guid.as_fields().0,
+    )
+}
+
+/// Writes the header, descriptor, grain directory and empty grain tables of an image
+pub fn create(file: &File, name: &str, size: u64, guid: uuid::Uuid) -> io::Result<()> {
+    let capacity = size.div_ceil(SECTOR_SIZE);
+    let grain_tables = capacity.div_ceil(GRAIN_SECTORS).div_ceil(GTES_PER_GT);
+    let gd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+    let gd_sectors = (grain_tables * 4).div_ceil(SECTOR_SIZE);
+    let gt_offset = gd_offset + gd_sectors;
+    let gt_sectors = GTES_PER_GT * 4 / SECTOR_SIZE;
+    // Grains are allocated after the preallocated grain tables
+    let overhead = (gt_offset + grain_tables * gt_sectors).next_multiple_of(GRAIN_SECTORS);
+    if gt_offset + grain_tables * gt_sectors > u32::MAX as u64 {
+        return Err(invalid_data(format!("disk size {size:#x} is too large")));
+    }
+
+    let descriptor = descriptor(name, capacity, guid);
+    if descriptor.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+        return Err(invalid_data(format!("image name '{name}' is too long")));
+    }
+
+    let mut header = vec![0; SECTOR_SIZE as usize];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    header[8..12].copy_from_slice(&FLAG_NEWLINE_TEST.to_le_bytes());
+    header[12..20].copy_from_slice(&capacity.to_le_bytes());
+    header[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+    header[28..36].copy_from_slice(&DESCRIPTOR_OFFSET.to_le_bytes());
+    header[36..44].copy_from_slice(&DESCRIPTOR_SECTORS.to_le_bytes());
+    header[44..48].copy_from_slice(&(GTES_PER_GT as u32).to_le_bytes());
+    header[56..64].copy_from_slice(&gd_offset.to_le_bytes());
+    header[64..72].copy_from_slice(&overhead.to_le_bytes());
+    header[73..77].copy_from_slice(b"\n \r\n");
+
+    file.set_len(overhead * SECTOR_SIZE)?;
+    file.write_all_at(&header, 0)?;
+    file.write_all_at(descriptor.as_bytes(), DESCRIPTOR_OFFSET * SECTOR_SIZE)?;
+    let grain_directory: Vec<u8> = (0..grain_tables)
+        .flat_map(|i| ((gt_offset + i * gt_sectors) as u32).to_le_bytes())
+        .collect();
+    file.write_all_at(&grain_directory, gd_offset * SECTOR_SIZE)
+}
+
+#[derive(Debug)]
+pub struct Vmdk {
+    file: File,
+    /// Disk size in sectors
+    capacity: u64,
+    grain_sectors: u64,
+    gtes_per_gt: u64,
+    /// Grain directories, the second one being the redundant copy if present
+    grain_directories: Vec<(u64, Vec<u32>)>,
+    /// Grain tables read so far, by grain directory index
+    grain_tables: BTreeMap<usize, Vec<u32>>,
+    /// Sector of the next grain appended to the file
+    next_sector: u64,
+}
+
+impl Vmdk {
+    pub fn open(file: File) -> io::Result<Self> {
+        let mut header = [0; SECTOR_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        let version = read_u32_le(&header, 4);
+        let flags = read_u32_le(&header, 8);
+        // Stream optimized images are compressed and cannot be written in place
+        if version > 2 || flags & FLAG_COMPRESSED != 0 || header[77..79] != [0, 0] {
+            return Err(invalid_data("compressed VMDK images are not supported"));
+        }
+
+        let capacity = read_u64_le(&header, 12);
+        let grain_sectors = read_u64_le(&header, 20);
+        let gtes_per_gt = read_u32_le(&header, 44) as u64;
+        if grain_sectors == 0 || gtes_per_gt == 0 {
+            return Err(invalid_data("invalid VMDK grain size"));
+        }
+        let grain_tables = capacity.div_ceil(grain_sectors).div_ceil(gtes_per_gt) as usize;
+
+        let mut gd_offsets = vec![read_u64_le(&header, 56)];
+        if flags & FLAG_REDUNDANT_GRAIN_TABLE != 0 {
+            gd_offsets.push(read_u64_le(&header, 48));
+        }
+        let mut grain_directories = Vec::new();
+        for gd_offset in gd_offsets {
+            let mut data = vec![0; grain_tables * 4];
+            file.read_exact_at(&mut data, gd_offset * SECTOR_SIZE)?;
+            let entries = data
+                .chunks_exact(4)
+                .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+                .collect();
+            grain_directories.push((gd_offset, entries));
+        }
+        let next_sector = file.metadata()?.len().div_ceil(SECTOR_SIZE);
+
+        Ok(Self {
+            file,
+            capacity,
+            grain_sectors,
+            gtes_per_gt,
+            grain_directories,
+            grain_tables: BTreeMap::new(),
+            next_sector,
+        })
+    }
+
+    /// Appends zeroed sectors to the file, returning the first one
+    fn allocate(&mut self, sectors: u64) -> io::Result<u64> {
+        let sector = self.next_sector;
+        self.next_sector += sectors;
+        self.file.set_len(self.next_sector * SECTOR_SIZE)?;
+        Ok(sector)
+    }
+
+    /// Reads a grain table of the primary grain directory,
+    /// returning false if it is not allocated
+    fn load_grain_table(&mut self, gd_index: usize) -> io::Result<bool> {
+        if self.grain_tables.contains_key(&gd_index) {
+            return Ok(true);
+        }
+        let gt_offset = self.grain_directories[0].1[gd_index] as u64;
+        if gt_offset == 0 {
+            return Ok(false);
+        }
+        let mut data = vec![0; self.gtes_per_gt as usize * 4];
+        self.file
+            .read_exact_at(&mut data, gt_offset * SECTOR_SIZE)?;
+        let table = data
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        self.grain_tables.insert(gd_index, table);
+        Ok(true)
+    }
+
+    /// Sector of a grain, 0 if it is not allocated
+    fn grain_entry(&mut self, grain: u64) -> io::Result<u64> {
+        let gd_index = (grain / self.gtes_per_gt) as usize;
+        if !self.load_grain_table(gd_index)? {
+            return Ok(0);
+        }
+        // Sector 1 marks grains that read as zeros
+        match self.grain_tables[&gd_index][(grain % self.gtes_per_gt) as usize] {
+            1 => Ok(0),
+            sector => Ok(sector as u64),
+        }
+    }
+
+    fn set_grain_entry(&mut self, grain: u64, sector: u64) -> io::Result<()> {
+        let gd_index = (grain / self.gtes_per_gt) as usize;
+        let gte_index = (grain % self.gtes_per_gt) as usize;
+        let sector = u32::try_from(sector)
+            .map_err(|_| invalid_data("VMDK image is larger than its grain tables can address"))?;
+        if !self.load_grain_table(gd_index)? {
+            let gt_sectors = (self.gtes_per_gt * 4).div_ceil(SECTOR_SIZE);
+            for i in 0..self.grain_directories.len() {
+                let gt_offset = self.allocate(gt_sectors)? as u32;
+                let (gd_offset, entries) = &mut self.grain_directories[i];
+                entries[gd_index] = gt_offset;
+                self.file.write_all_at(
+                    &gt_offset.to_le_bytes(),
+                    *gd_offset * SECTOR_SIZE + gd_index as u64 * 4,
+                )?;
+            }
+            self.grain_tables
+                .insert(gd_index, vec![0; self.gtes_per_gt as usize]);
+        }
+        self.grain_tables.get_mut(&gd_index).unwrap()[gte_index] = sector;
+        // Keep the redundant grain tables in sync
+        for (_, entries) in &self.grain_directories {
+            let gt_offset = entries[gd_index] as u64;
+            self.file.write_all_at(
+                &sector.to_le_bytes(),
+                gt_offset * SECTOR_SIZE + gte_index as u64 * 4,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtualDisk for Vmdk {
+    fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let grain_size = self.grain_sectors * SECTOR_SIZE;
+        for_each_unit(
+            offset,
+            buf.len(),
+            grain_size,
+            |grain, in_grain, range| match self.grain_entry(grain)? {
+                0 => {
+                    buf[range].fill(0);
+                    Ok(())
+                }
+                sector => self
+                    .file
+                    .read_exact_at(&mut buf[range], sector * SECTOR_SIZE + in_grain),
+            },
+        )
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let grain_size = self.grain_sectors * SECTOR_SIZE;
+        for_each_unit(offset, buf.len(), grain_size, |grain, in_grain, range| {
+            let sector = match self.grain_entry(grain)? {
+                0 => {
+                    // Unallocated grains already read as zeros
+                    if buf[range.clone()].iter().all(|&b| b == 0) {
+                        return Ok(());
+                    }
+                    let sector = self.allocate(self.grain_sectors)?;
+                    self.file
+                        .write_all_at(&buf[range], sector * SECTOR_SIZE + in_grain)?;
+                    return self.set_grain_entry(grain, sector);
+                }
+                sector => sector,
+            };
+            self.file
+                .write_all_at(&buf[range], sector * SECTOR_SIZE + in_grain)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.file)
+    }
+}
@@ -1,26 +1,41 @@
 use anyhow::Context;
 use anyhow::{bail, Result};
-use pkg::Library;
 use rand::{rngs::OsRng, TryRngCore};
 use redoxfs::{unmount_path, Disk, DiskIo, FileSystem, BLOCK_SIZE};
 use termion::input::TermRead;
 
-use crate::config::file::FileConfig;
+use crate::config::file::{format_bytes, FileConfig};
+use crate::config::general::{FilesystemSize, ImageFormat, MIN_FILESYSTEM_SIZE};
+use crate::config::lock::{LockFile, LockedPackage};
 use crate::config::package::PackageConfig;
+use crate::config::partition::{
+    PartitionConfig, PartitionFilesystem, PartitionScheme, PartitionSize,
+};
+use crate::config::validate::Severity;
 use crate::config::Config;
-use crate::disk_wrapper::DiskWrapper;
+use crate::disk_wrapper::{DiskWrapper, DEFAULT_BLOCK_SIZE};
+use crate::image;
+use crate::package_source::{
+    blake3_file, check_locked_pkgar, lock_packages, package_meta, package_source, packages_size,
+    PackageSource,
+};
+use crate::partition::{
+    free_space_range, gpt_block_size, is_redoxfs_part_type, mbr_partition_table, mbr_type,
+    partition_layout, redoxfs_partition, PartitionPlan, REDOXFS_MBR_TYPE, REDOXFS_PART_TYPE,
+};
+use crate::reproducible::Reproducible;
+use crate::target::{
+    check_redoxfs_partition, find_gpt_partition, gpt_partitions, PartitionSelector,
+};
 
 use std::{
-    cell::RefCell,
     collections::BTreeMap,
     env, fs,
-    io::{self, Seek, SeekFrom, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     process,
-    rc::Rc,
     sync::mpsc::channel,
     thread,
-    time::{SystemTime, UNIX_EPOCH},
 };
 
 pub struct DiskOption<'a> {
@@ -29,6 +44,15 @@ pub struct DiskOption<'a> {
     pub password_opt: Option<&'a [u8]>,
     pub efi_partition_size: Option<u32>, //MiB
     pub skip_partitions: bool,
+    pub partition_scheme: PartitionScheme,
+    /// Logical block size of image files, block devices use their own
+    pub block_size: Option<u32>,
+    /// Partition layout, empty for the default BIOS, EFI and RedoxFS partitions
+    pub partitions: &'a [PartitionConfig],
+    /// GUIDs and timestamps of the partitions and filesystems
+    pub reproducible: Reproducible,
+    /// Cut a raw image file after a RedoxFS that was shrunk to its content
+    pub shrink: bool,
 }
 
 pub fn get_target() -> String {
@@ -38,11 +62,28 @@ pub fn get_target() -> String {
     )
 }
 
+/// Name of the UEFI bootloader in EFI/BOOT for a target
+pub(crate) fn efi_bootloader_name(target: &str) -> Result<&'static str> {
+    Ok(match target {
+        "aarch64-unknown-redox" => "BOOTAA64.EFI",
+        "i586-unknown-redox" | "i686-unknown-redox" => "BOOTIA32.EFI",
+        "x86_64-unknown-redox" => "BOOTX64.EFI",
+        "riscv64gc-unknown-redox" => "BOOTRISCV64.EFI",
+        _ => {
+            bail!("target '{target}' not supported");
+        }
+    })
+}
+
 /// Converts a password to a serialized argon2rs hash, understandable
 /// by redox_users. If the password is blank, the hash is blank.
-fn hash_password(password: &str) -> Result<String> {
+pub fn hash_password(password: &str) -> Result<String> {
+    hash_password_salted(password, &format!("{:X}", OsRng.try_next_u64()?))
+}
+
+/// Same as [hash_password], with a given salt instead of a random one
+pub fn hash_password_salted(password: &str, salt: &str) -> Result<String> {
     if !password.is_empty() {
-        let salt = format!("{:X}", OsRng.try_next_u64()?);
         let config = argon2::Config::default();
         let hash = argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config)?;
         Ok(hash)
@@ -51,7 +92,16 @@ fn hash_password(password: &str) -> Result<String> {
     }
 }
 
-fn syscall_error(err: syscall::Error) -> io::Error {
+/// Checks that a pre-hashed password is a serialized argon2 hash
+/// understandable by redox_users. A blank hash means no password.
+pub fn check_password_hash(hash: &str) -> Result<()> {
+    if !hash.is_empty() {
+        argon2::verify_encoded(hash, b"").context("not an argon2 encoded hash")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn syscall_error(err: syscall::Error) -> io::Error {
     io::Error::from_raw_os_error(err.errno)
 }
 
@@ -87,102 +137,219 @@ pub fn prompt_password(prompt: &str, confirm_prompt: &str) -> Result<Option<Stri
     bail!("passwords do not match, giving up");
 }
 
-fn install_packages(config: &Config, dest: &Path, cookbook: Option<&str>) -> anyhow::Result<()> {
-    let target = &get_target();
+fn install_packages(config: &Config, dest: &Path, source: &dyn PackageSource) -> Result<()> {
+    let mut packages: Vec<String> = Vec::new();
+    let mut local_packages: Vec<(&String, &String)> = Vec::new();
+    for (packagename, package) in &config.packages {
+        match package {
+            PackageConfig::Build(rule) if rule == "ignore" => (),
+            PackageConfig::Spec { git: Some(_), .. } => {
+                bail!("package {packagename}: git sources are not supported by the installer");
+            }
+            PackageConfig::Spec {
+                version: Some(_),
+                path: Some(_),
+                ..
+            } => {
+                bail!("package {packagename}: version cannot be checked for a local path");
+            }
+            PackageConfig::Spec {
+                path: Some(path), ..
+            } => local_packages.push((packagename, path)),
+            PackageConfig::Spec {
+                version: Some(version),
+                ..
+            } => {
+                // The repo only serves one version of each package, so pinning means checking
+                let found = package_meta(source, packagename)?.version;
+                if &found != version {
+                    bail!("package {packagename}: version {version} requested, but repo has version '{found}'");
+                }
+                packages.push(packagename.clone());
+            }
+            _ => packages.push(packagename.clone()),
+        }
+    }
+
+    let mut locked = None;
+    let mut lockfile = None;
+    if let Some(path) = &config.general.lockfile {
+        if config.general.locked.unwrap_or(false) {
+            // Packages are installed by their locked hashes, without the current repo metadata
+            locked = Some(LockFile::from_file(Path::new(path))?);
+        } else {
+            // The bootloader is always locked, for locked installs of disks to fetch it
+            let mut lock_names = packages.clone();
+            for name in Config::bootloader_config().packages.into_keys() {
+                if !config.packages.contains_key(&name) {
+                    lock_names.push(name);
+                }
+            }
+            let resolved = lock_packages(source, &lock_names)?;
+            lockfile = Some((path, resolved));
+        }
+    }
 
-    let packages: Vec<&String> = config
-        .packages
-        .iter()
-        .filter_map(|(packagename, package)| match package {
-            PackageConfig::Build(rule) if rule == "ignore" => None,
-            _ => Some(packagename),
-        })
-        .collect();
+    match &locked {
+        Some(locked) => source.install_locked(&packages, locked, dest)?,
+        None => source.install(&packages, dest)?,
+    }
 
-    let mut library = if let Some(cookbook) = cookbook {
-        let callback = pkg::callback::PlainCallback::new();
-        let repo = Path::new(cookbook).join("repo");
-        let pubkey = Path::new(cookbook).join("build");
-        Library::new_local(
-            repo,
-            pubkey,
-            dest.to_path_buf(),
-            target,
-            Rc::new(RefCell::new(callback)),
-        )
-    } else {
-        let callback = pkg::callback::IndicatifCallback::new();
-        Library::new_remote(
-            &vec!["https://static.redox-os.org/pkg"],
-            dest,
-            target,
-            Rc::new(RefCell::new(callback)),
-        )
-    }?;
-
-    let packages = pkg::PackageName::from_list(packages)?;
-    library.install(packages)?;
-    library.apply()?;
+    // Local packages are installed last so that they replace any repo package
+    for (packagename, path) in &local_packages {
+        if let Some(locked) = &locked {
+            check_locked_pkgar(packagename, Path::new(path), locked)?;
+        }
+        source.install_pkgar(Path::new(path), dest)?;
+    }
+
+    if let Some((path, mut resolved)) = lockfile {
+        for (packagename, local_path) in local_packages {
+            resolved.packages.insert(
+                packagename.clone(),
+                LockedPackage {
+                    version: String::new(),
+                    blake3: blake3_file(Path::new(local_path))?,
+                    source: format!("file://{local_path}"),
+                    target: get_target(),
+                    depends: Vec::new(),
+                },
+            );
+        }
+        println!("Write lockfile {path}");
+        resolved.to_file(Path::new(path))?;
+    }
 
     Ok(())
 }
 
+/// RedoxFS metadata and partly used blocks of packages, in percent of their size
+const AUTO_SIZE_OVERHEAD: u64 = 10;
+/// Free space added to an "auto" filesystem_size by default, in percent of the content
+const DEFAULT_HEADROOM: u32 = 20;
+
+/// Filesystem size in MB of a config, computing "auto" from the packages of the source,
+/// or 0 if unset
+pub fn filesystem_size(config: &Config, source: &dyn PackageSource) -> Result<u32> {
+    match config.general.filesystem_size {
+        Some(FilesystemSize::Mb(size)) => Ok(size),
+        Some(FilesystemSize::Auto) => auto_filesystem_size(config, source),
+        None => Ok(0),
+    }
+}
+
+/// Size in MB of a disk fitting the packages, files and home directories of a config
+/// with its headroom, and the partitions besides RedoxFS
+fn auto_filesystem_size(config: &Config, source: &dyn PackageSource) -> Result<u32> {
+    let mut packages = Vec::new();
+    let mut content = 0;
+    for (packagename, package) in &config.packages {
+        match package {
+            PackageConfig::Build(rule) if rule == "ignore" => (),
+            PackageConfig::Spec {
+                path: Some(path), ..
+            } => content += source.pkgar_size(Path::new(path))?,
+            _ => packages.push(packagename.clone()),
+        }
+    }
+    content += packages_size(source, &packages)?;
+    content = content * (100 + AUTO_SIZE_OVERHEAD) / 100;
+
+    // Every file and directory takes a node block, and data is stored in whole blocks
+    for file in &config.files {
+        content += BLOCK_SIZE + (file.data.len() as u64).next_multiple_of(BLOCK_SIZE);
+    }
+    let ids = config.assigned_ids();
+    for username in config.users.keys() {
+        // The XDG directories and user-dirs.dirs of regular users, see prepare_user_home
+        let nodes = if ids.users[username].0 >= 1000 { 15 } else { 1 };
+        content += nodes * BLOCK_SIZE;
+    }
+
+    let headroom = config
+        .general
+        .filesystem_headroom
+        .unwrap_or(DEFAULT_HEADROOM) as u64;
+    let mut disk_size = content * (100 + headroom) / 100;
+    if !config.general.skip_partitions.unwrap_or(false) {
+        for partition in config.partition_layout() {
+            if let PartitionSize::Mib(size) = partition.size {
+                disk_size += size as u64 * 1024 * 1024;
+            }
+        }
+        // Room for both GPT tables and the alignment of partitions to 1 MiB
+        disk_size += 2 * 1024 * 1024;
+    }
+    let size = disk_size
+        .div_ceil(1024 * 1024)
+        .max(MIN_FILESYSTEM_SIZE as u64);
+    u32::try_from(size).with_context(|| format!("filesystem size of {size} MB is too large"))
+}
+
 pub fn install_dir(
     config: Config,
     output_dir: impl AsRef<Path>,
-    cookbook: Option<&str>,
+    source: &dyn PackageSource,
 ) -> Result<()> {
     let output_dir = output_dir.as_ref();
 
     let output_dir = output_dir.to_owned();
+    let reproducible = Reproducible::new(config.general.reproducible_seed.as_deref())?;
+    let update = config.general.update.unwrap_or(false);
 
     for file in &config.files {
         if !file.postinstall {
-            file.create(&output_dir)?;
+            file.install(&output_dir, update)?;
         }
     }
 
-    install_packages(&config, &output_dir, cookbook)?;
+    install_packages(&config, &output_dir, source)?;
 
     for file in &config.files {
         if file.postinstall {
+            file.install(&output_dir, update)?;
+        }
+    }
+
+    // Written after packages to replace the repository files they ship
+    for repository in &config.repositories {
+        for file in repository.pkg_d_files()? {
             file.create(&output_dir)?;
         }
     }
 
     let mut passwd = String::new();
     let mut shadow = String::new();
-    let mut next_uid = 1000;
-    let mut next_gid = 1000;
+    let ids = config.assigned_ids();
 
     let mut groups = vec![];
 
     for (username, user) in config.users {
-        // plaintext
-        let password = if let Some(password) = user.password {
-            password
-        } else if config.general.prompt.unwrap_or(true) {
-            prompt_password(
-                &format!("{}: enter password: ", username),
-                &format!("{}: confirm password: ", username),
-            )?
-            .unwrap_or_default()
-        } else {
-            String::new()
+        let password = match (user.password, user.password_hash) {
+            (Some(_), Some(_)) => {
+                bail!("user {username}: password and password_hash are mutually exclusive");
+            }
+            (None, Some(password_hash)) => {
+                check_password_hash(&password_hash)
+                    .with_context(|| format!("user {username}: invalid password_hash"))?;
+                password_hash
+            }
+            // plaintext
+            (Some(password), None) => {
+                hash_password_salted(&password, &reproducible.salt(&username)?)?
+            }
+            (None, None) if config.general.prompt.unwrap_or(true) => hash_password_salted(
+                &prompt_password(
+                    &format!("{}: enter password: ", username),
+                    &format!("{}: confirm password: ", username),
+                )?
+                .unwrap_or_default(),
+                &reproducible.salt(&username)?,
+            )?,
+            (None, None) => String::new(),
         };
 
-        let uid = user.uid.unwrap_or(next_uid);
-
-        if uid >= next_uid {
-            next_uid = uid + 1;
-        }
-
-        let gid = user.gid.unwrap_or(next_gid);
-
-        if gid >= next_gid {
-            next_gid = gid + 1;
-        }
-
+        let (uid, gid) = ids.users[&username];
         let name = user.name.unwrap_or(username.clone());
         let home = user.home.unwrap_or(format!("/home/{}", username));
         let shell = user.shell.unwrap_or("/bin/ion".into());
@@ -199,30 +366,28 @@ pub fn install_dir(
         println!("\tHome: {home}");
         println!("\tShell: {shell}");
 
-        FileConfig::new_directory(home.clone())
-            .with_recursive_mod(0o700, uid, gid)
-            .create(&output_dir)?;
+        // Updates leave existing homes as their users left them
+        let home_dir = output_dir.join(home.trim_start_matches('/'));
+        if update && home_dir.exists() {
+            println!("Keep existing home {}", home_dir.display());
+        } else {
+            FileConfig::new_directory(home.clone())
+                .with_recursive_mod(0o700, uid, gid)
+                .create(&output_dir)?;
 
-        if uid >= 1000 {
-            prepare_user_home(&output_dir, uid, gid, &home)?;
+            if uid >= 1000 {
+                prepare_user_home(&output_dir, uid, gid, &home)?;
+            }
         }
 
-        let password = hash_password(&password)?;
-
         passwd.push_str(&format!("{username};{uid};{gid};{name};{home};{shell}\n",));
         shadow.push_str(&format!("{username};{password}\n"));
         groups.push((username.clone(), gid, vec![username]));
     }
 
     for (group, group_config) in config.groups {
-        // FIXME this assumes there is no overlap between auto-created groups for users
-        // and explicitly specified groups.
-        let gid = group_config.gid.unwrap_or(next_gid);
-
-        if gid >= next_gid {
-            next_gid = gid + 1;
-        }
-
+        // Overlap with auto-created groups for users is reported by Config::validate
+        let gid = ids.groups[&group];
         groups.push((group, gid, group_config.members));
     }
 
@@ -253,6 +418,11 @@ pub fn install_dir(
             .create(&output_dir)?;
     }
 
+    // Files kept by an update keep their times
+    if !update {
+        reproducible.set_times(&output_dir)?;
+    }
+
     Ok(())
 }
 
@@ -338,14 +508,27 @@ fn copy_dir_all(
     Ok(())
 }
 
-pub fn with_redoxfs<D, T, F>(disk: D, password_opt: Option<&[u8]>, callback: F) -> Result<T>
+pub fn with_redoxfs<D, T, F>(
+    disk: D,
+    password_opt: Option<&[u8]>,
+    reproducible: &Reproducible,
+    callback: F,
+) -> Result<T>
 where
     D: Disk + Send + 'static,
     F: FnOnce(FileSystem<D>) -> Result<T>,
 {
-    let ctime = SystemTime::now().duration_since(UNIX_EPOCH)?;
-    let fs = FileSystem::create(disk, password_opt, ctime.as_secs(), ctime.subsec_nanos())
+    let ctime = reproducible.ctime()?;
+    let mut fs = FileSystem::create(disk, password_opt, ctime.as_secs(), ctime.subsec_nanos())
         .map_err(syscall_error)?;
+    if let Some(uuid) = reproducible.seeded_guid("redoxfs") {
+        fs.tx(|tx| {
+            tx.header.uuid = *uuid.as_bytes();
+            tx.header_changed = true;
+            tx.sync(false)
+        })
+        .map_err(syscall_error)?;
+    }
     callback(fs)
 }
 
@@ -416,9 +599,14 @@ where
     res
 }
 
+/// Writes the files of a directory into the filesystem with RedoxFS AR instead of mounting it.
+/// Its nodes then get the times of the reproducible install. With shrink, the filesystem
+/// is then cut down to its content, see [shrink_redoxfs].
 pub fn with_redoxfs_ar<D, T, F>(
     mut fs: FileSystem<D>,
     mount_path: Option<&Path>,
+    reproducible: &Reproducible,
+    shrink: bool,
     callback: F,
 ) -> Result<T>
 where
@@ -430,56 +618,178 @@ where
     let res = callback(Path::new(&mount_path));
 
     if res.is_ok() {
-        let _end_block = fs
-            .tx(|tx| {
-                // Archive_at root node
-                redoxfs::archive_at(tx, Path::new(&mount_path), redoxfs::TreePtr::root())
-                    .map_err(|err| syscall::Error::new(err.raw_os_error().unwrap()))?;
-
-                // Squash alloc log
-                tx.sync(true)?;
-
-                let end_block = tx.header.size() / BLOCK_SIZE;
-                /* TODO: Cut off any free blocks at the end of the filesystem
-                let mut end_changed = true;
-                while end_changed {
-                    end_changed = false;
-
-                    let allocator = fs.allocator();
-                    let levels = allocator.levels();
-                    for level in 0..levels.len() {
-                        let level_size = 1 << level;
-                        for &block in levels[level].iter() {
-                            if block < end_block && block + level_size >= end_block {
-                                end_block = block;
-                                end_changed = true;
-                            }
-                        }
-                    }
+        fs.tx(|tx| {
+            // Archive_at root node
+            redoxfs::archive_at(tx, Path::new(&mount_path), redoxfs::TreePtr::root())
+                .map_err(|err| syscall::Error::new(err.raw_os_error().unwrap()))?;
+
+            // Squash alloc log
+            tx.sync(true)?;
+            Ok(())
+        })
+        .map_err(syscall_error)?;
+        reproducible.set_redoxfs_times(&mut fs)?;
+
+        if shrink {
+            let size = shrink_redoxfs(&mut fs)?;
+            eprintln!("Shrunk RedoxFS to its content, {}", format_bytes(size));
+        }
+    }
+
+    fs::remove_dir_all(&mount_path)?;
+
+    res
+}
+
+/// Free blocks kept at the end of a shrunk RedoxFS, for the rewritten allocation log
+const SHRINK_RESERVE_BLOCKS: u64 = 16;
+
+/// Cuts free blocks off the end of a RedoxFS, returning its new size in bytes.
+/// The disk is left as is, callers truncate it with the size from the header.
+pub fn shrink_redoxfs<D: Disk>(fs: &mut FileSystem<D>) -> Result<u64> {
+    let size_block = fs.header.size() / BLOCK_SIZE;
+
+    // Cut off any free blocks at the end of the filesystem
+    let mut end_block = size_block;
+    let mut end_changed = true;
+    while end_changed {
+        end_changed = false;
+
+        let levels = fs.allocator().levels();
+        for (level, blocks) in levels.iter().enumerate() {
+            let level_size = 1 << level;
+            for &block in blocks.iter() {
+                if block < end_block && block + level_size >= end_block {
+                    end_block = block;
+                    end_changed = true;
                 }
-                */
+            }
+        }
+    }
+    let end_block = (end_block + SHRINK_RESERVE_BLOCKS).min(size_block);
+
+    // Mark the cut blocks as used, so that the allocation log rewritten below
+    // no longer lists them as free
+    let allocator = unsafe { fs.allocator_mut() };
+    for block in end_block..size_block {
+        allocator.allocate_exact(redoxfs::BlockAddr::new(
+            block,
+            redoxfs::BlockMeta::default(),
+        ));
+    }
 
-                // Update header
-                tx.header.size = (end_block * BLOCK_SIZE).into();
-                tx.header_changed = true;
-                tx.sync(false)?;
+    fs.tx(|tx| {
+        // Update header
+        tx.header.size = (end_block * BLOCK_SIZE).into();
+        tx.header_changed = true;
 
-                Ok(end_block)
-            })
-            .map_err(syscall_error)?;
+        // Squash alloc log, rewriting it from the allocator
+        tx.sync(true)?;
+        Ok(())
+    })
+    .map_err(syscall_error)?;
 
-        // let size = (fs.block + end_block) * BLOCK_SIZE;
-        // fs.disk.file.set_len(size)?;
+    Ok(end_block * BLOCK_SIZE)
+}
+
+/// Grows a RedoxFS to a size in bytes, freeing the added blocks, and returns its new size.
+/// The disk must already be large enough.
+pub fn grow_redoxfs<D: Disk>(fs: &mut FileSystem<D>, size: u64) -> Result<u64> {
+    let size_block = fs.header.size() / BLOCK_SIZE;
+    let end_block = size / BLOCK_SIZE;
+    if end_block <= size_block {
+        return Ok(size_block * BLOCK_SIZE);
     }
 
-    fs::remove_dir_all(&mount_path)?;
+    // Free the added blocks, so that the allocation log rewritten below lists them
+    let allocator = unsafe { fs.allocator_mut() };
+    for block in size_block..end_block {
+        allocator.deallocate(redoxfs::BlockAddr::new(
+            block,
+            redoxfs::BlockMeta::default(),
+        ));
+    }
 
-    res
+    fs.tx(|tx| {
+        // Update header
+        tx.header.size = (end_block * BLOCK_SIZE).into();
+        tx.header_changed = true;
+
+        // Squash alloc log, rewriting it from the allocator
+        tx.sync(true)?;
+        Ok(())
+    })
+    .map_err(syscall_error)?;
+
+    Ok(end_block * BLOCK_SIZE)
+}
+
+/// Size of the RedoxFS in a range of a disk, from its header
+fn redoxfs_size(disk_path: &Path, disk_option: &DiskOption, start: u64, end: u64) -> Result<u64> {
+    let disk_file = DiskWrapper::open(disk_path, disk_option.block_size.map(|size| size as usize))?;
+    let fs = FileSystem::open(
+        DiskIo(fscommon::StreamSlice::new(disk_file, start, end)?),
+        disk_option.password_opt,
+        None,
+        false,
+    )?;
+    Ok(fs.header.size())
+}
+
+/// Sets the times of the RedoxFS nodes written by a mount, see [Reproducible::set_redoxfs_times]
+fn set_redoxfs_times(
+    disk_path: &Path,
+    disk_option: &DiskOption,
+    start: u64,
+    end: u64,
+) -> Result<()> {
+    // Opening the disk unlocks encrypted filesystems, so skip it when there is nothing to set
+    if disk_option.reproducible.epoch().is_none() {
+        return Ok(());
+    }
+    let disk_file = DiskWrapper::open(disk_path, disk_option.block_size.map(|size| size as usize))?;
+    let mut fs = FileSystem::open(
+        DiskIo(fscommon::StreamSlice::new(disk_file, start, end)?),
+        disk_option.password_opt,
+        None,
+        false,
+    )?;
+    disk_option.reproducible.set_redoxfs_times(&mut fs)
+}
+
+/// Ends a layout whose last partition holds a shrunk RedoxFS right after the filesystem,
+/// returning the size of the disk cut after it
+fn fit_layout_to_redoxfs(
+    disk_path: &Path,
+    disk_option: &DiskOption,
+    layout: &mut [PartitionPlan],
+    block_size: u64,
+) -> Result<Option<u64>> {
+    let Some(last_partition) = layout.last_mut() else {
+        return Ok(None);
+    };
+    if last_partition.filesystem != PartitionFilesystem::Redoxfs {
+        return Ok(None);
+    }
+    let (start, end) = last_partition.byte_range(block_size);
+    let redoxfs_size = redoxfs_size(disk_path, disk_option, start, end)?;
+    if redoxfs_size >= end - start {
+        return Ok(None);
+    }
+    let redoxfs_end = (start + redoxfs_size).next_multiple_of(block_size);
+    last_partition.last_lba = redoxfs_end / block_size - 1;
+    // Keep room for the GPT table mirror
+    Ok(Some(redoxfs_end + block_size + 128 * 128))
+}
+
+/// Whether a disk is an image file that can be truncated
+fn is_raw_image_file(disk_path: &Path) -> Result<bool> {
+    Ok(disk_path.is_file() && image::detect(&fs::File::open(disk_path)?)? == ImageFormat::Raw)
 }
 
 pub fn fetch_bootloaders(
     config: &Config,
-    cookbook: Option<&str>,
+    source: &dyn PackageSource,
     live: bool,
 ) -> Result<(Vec<u8>, Vec<u8>)> {
     let bootloader_dir =
@@ -493,7 +803,11 @@ pub fn fetch_bootloaders(
 
     let mut bootloader_config = Config::bootloader_config();
     bootloader_config.general = config.general.clone();
-    install_packages(&bootloader_config, &bootloader_dir, cookbook)?;
+    if !config.general.locked.unwrap_or(false) {
+        // The lockfile is written by the main install, which always locks the bootloader
+        bootloader_config.general.lockfile = None;
+    }
+    install_packages(&bootloader_config, &bootloader_dir, source)?;
 
     let boot_dir = bootloader_dir.join("usr/lib/boot");
     let bios_path = boot_dir.join(if live {
@@ -523,69 +837,188 @@ pub fn fetch_bootloaders(
     Ok((bios_data, efi_data))
 }
 
-//TODO: make bootloaders use Option, dynamically create BIOS and EFI partitions
+/// Returns the RedoxFS partitions in the GPT of a disk, recognizing both the
+/// RedoxFS partition type and the LINUX_FS type written by older installers
+pub fn redoxfs_partitions(disk_path: impl AsRef<Path>) -> Result<Vec<gpt::partition::Partition>> {
+    Ok(gpt_partitions(disk_path, None)?
+        .into_values()
+        .filter(|partition| is_redoxfs_part_type(&partition.part_type_guid))
+        .collect())
+}
+
+/// Copies a directory of the build host into a FAT filesystem
+fn copy_dir_to_fat<T: fatfs::ReadWriteSeek>(src: &Path, dir: &fatfs::Dir<T>) -> Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            bail!("{}: file name is not valid UTF-8", path.display());
+        };
+        if entry.file_type()?.is_dir() {
+            copy_dir_to_fat(&path, &dir.create_dir(&name)?)?;
+        } else {
+            let mut file = dir.create_file(&name)?;
+            file.truncate()?;
+            io::copy(&mut fs::File::open(&path)?, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a protective MBR and GPT tables with the partitions of a layout
+fn write_gpt(
+    disk_file: &mut DiskWrapper,
+    layout: &[PartitionPlan],
+    disk_size: u64,
+    block_size: u64,
+    reproducible: &Reproducible,
+) -> Result<()> {
+    // Add configured partitions, by default BIOS boot, EFI boot and RedoxFS
+    let mut partitions = BTreeMap::new();
+    for (i, partition) in layout.iter().enumerate() {
+        partitions.insert(
+            i as u32 + 1,
+            gpt::partition::Partition {
+                part_type_guid: partition.part_type.clone(),
+                part_guid: reproducible.guid(&format!("partition{}", i + 1)),
+                first_lba: partition.first_lba,
+                last_lba: partition.last_lba,
+                flags: 0, // TODO
+                name: partition.name.clone(),
+            },
+        );
+    }
+
+    write_gpt_partitions(
+        disk_file,
+        partitions,
+        reproducible.guid("disk"),
+        disk_size,
+        block_size,
+    )
+}
+
+/// Writes a protective MBR and new GPT tables at both ends of the disk, holding the partitions
+fn write_gpt_partitions(
+    disk_file: &mut DiskWrapper,
+    partitions: BTreeMap<u32, gpt::partition::Partition>,
+    disk_guid: uuid::Uuid,
+    disk_size: u64,
+    block_size: u64,
+) -> Result<()> {
+    // Replace MBR tables with protective MBR
+    // TODO: div_ceil
+    let mbr_blocks = ((disk_size + block_size - 1) / block_size) - 1;
+    eprintln!("Writing protective MBR with disk blocks {mbr_blocks:#x}");
+    gpt::mbr::ProtectiveMBR::with_lb_size(mbr_blocks as u32).update_conservative(disk_file)?;
+
+    // Open disk, mark it as not initialized
+    let mut gpt_disk = gpt::GptConfig::new()
+        .initialized(false)
+        .writable(true)
+        .logical_block_size(gpt_block_size(block_size)?)
+        .create_from_device(Box::new(disk_file), Some(disk_guid))?;
+
+    eprintln!("Writing GPT tables: {partitions:#?}");
+
+    // Initialize GPT table
+    gpt_disk.update_partitions(partitions)?;
+
+    // Write partition layout, returning disk file
+    gpt_disk.write()?;
+    Ok(())
+}
+
+/// Writes the partition table of a layout in the configured scheme
+fn write_partition_table(
+    disk_file: &mut DiskWrapper,
+    disk_option: &DiskOption,
+    layout: &[PartitionPlan],
+    disk_size: u64,
+    block_size: u64,
+) -> Result<()> {
+    match disk_option.partition_scheme {
+        PartitionScheme::Gpt => write_gpt(
+            disk_file,
+            layout,
+            disk_size,
+            block_size,
+            &disk_option.reproducible,
+        )?,
+        PartitionScheme::Mbr => {
+            // Replace MBR tables with the partitions, keeping the bootloader code before them
+            let table = mbr_partition_table(layout)?;
+            eprintln!("Writing MBR partition table");
+            disk_file.seek(SeekFrom::Start(446))?;
+            disk_file.write_all(&table)?;
+            disk_file.write_all(&[0x55, 0xAA])?;
+
+            // GPT headers of an earlier install would make tools read a stale GPT
+            let last_block = disk_file.size() / block_size - 1;
+            for lba in [1, last_block] {
+                let mut header = vec![0; block_size as usize];
+                disk_file.seek(SeekFrom::Start(lba * block_size))?;
+                disk_file.read_exact(&mut header)?;
+                if header.starts_with(GPT_SIGNATURE) {
+                    eprintln!("Clearing GPT header at LBA {lba}");
+                    header.fill(0);
+                    disk_file.seek(SeekFrom::Start(lba * block_size))?;
+                    disk_file.write_all(&header)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//TODO: make bootloaders use Option
 pub fn with_whole_disk<P, F, T>(disk_path: P, disk_option: &DiskOption, callback: F) -> Result<T>
 where
     P: AsRef<Path>,
     F: FnOnce(FileSystem<DiskIo<fscommon::StreamSlice<DiskWrapper>>>) -> Result<T>,
 {
-    let target = get_target();
-
-    let bootloader_efi_name = match target.as_str() {
-        "aarch64-unknown-redox" => "BOOTAA64.EFI",
-        "i586-unknown-redox" | "i686-unknown-redox" => "BOOTIA32.EFI",
-        "x86_64-unknown-redox" => "BOOTX64.EFI",
-        "riscv64gc-unknown-redox" => "BOOTRISCV64.EFI",
-        _ => {
-            bail!("target '{target}' not supported");
-        }
-    };
+    let bootloader_efi_name = efi_bootloader_name(&get_target())?;
     // Open disk and read metadata
     eprintln!("Opening disk {}", disk_path.as_ref().display());
-    let mut disk_file = DiskWrapper::open(disk_path.as_ref())?;
+    let mut disk_file = DiskWrapper::open(
+        disk_path.as_ref(),
+        disk_option.block_size.map(|size| size as usize),
+    )?;
     let disk_size = disk_file.size();
     let block_size = disk_file.block_size() as u64;
+    let disk_path = disk_path.as_ref();
+    let truncate = disk_option.shrink && is_raw_image_file(disk_path)?;
 
     if disk_option.skip_partitions {
-        return with_redoxfs(
-            DiskIo(fscommon::StreamSlice::new(
-                disk_file,
-                0,
-                disk_size.next_multiple_of(block_size),
-            )?),
+        let disk_end = disk_size.next_multiple_of(block_size);
+        let res = with_redoxfs(
+            DiskIo(fscommon::StreamSlice::new(disk_file, 0, disk_end)?),
             disk_option.password_opt,
+            &disk_option.reproducible,
             callback,
-        );
-    }
-
-    let gpt_block_size = match block_size {
-        512 => gpt::disk::LogicalBlockSize::Lb512,
-        _ => {
-            // TODO: support (and test) other block sizes
-            bail!("block size {block_size} not supported");
+        )?;
+        set_redoxfs_times(disk_path, disk_option, 0, disk_end)?;
+        if truncate {
+            // Cut off what a shrunk RedoxFS no longer uses
+            let redoxfs_size = redoxfs_size(disk_path, disk_option, 0, disk_end)?;
+            if redoxfs_size < disk_size {
+                eprintln!("Truncating disk to {}", format_bytes(redoxfs_size));
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(disk_path)?
+                    .set_len(redoxfs_size)?;
+            }
         }
-    };
-
-    // Calculate partition offsets
-    let gpt_reserved = 34 * 512; // GPT always reserves 34 512-byte sectors
-    let mibi = 1024 * 1024;
-
-    // First megabyte of the disk is reserved for BIOS partition, wich includes GPT tables
-    let bios_start = gpt_reserved / block_size;
-    let bios_end = (mibi / block_size) - 1;
+        return Ok(res);
+    }
 
-    // Second megabyte of the disk is reserved for EFI partition
-    let efi_start = bios_end + 1;
-    let efi_size = if let Some(size) = disk_option.efi_partition_size {
-        size as u64
+    let partitions = if disk_option.partitions.is_empty() {
+        PartitionConfig::default_layout(disk_option.efi_partition_size)
     } else {
-        1
+        disk_option.partitions.to_vec()
     };
-    let efi_end = efi_start + (efi_size * mibi / block_size) - 1;
-
-    // The rest of the disk is RedoxFS, reserving the GPT table mirror at the end of disk
-    let redoxfs_start = efi_end + 1;
-    let redoxfs_end = ((((disk_size - gpt_reserved) / mibi) * mibi) / block_size) - 1;
+    let mut layout = partition_layout(disk_size, block_size, &partitions)?;
+    let (disk_redoxfs_start, disk_redoxfs_end) = redoxfs_partition(&layout)?.byte_range(block_size);
 
     // Format and install BIOS partition
     {
@@ -596,120 +1029,653 @@ where
         );
         disk_file.seek(SeekFrom::Start(0))?;
         disk_file.write_all(&disk_option.bootloader_bios)?;
+    }
 
-        // Replace MBR tables with protective MBR
-        // TODO: div_ceil
-        let mbr_blocks = ((disk_size + block_size - 1) / block_size) - 1;
-        eprintln!("Writing protective MBR with disk blocks {mbr_blocks:#x}");
-        gpt::mbr::ProtectiveMBR::with_lb_size(mbr_blocks as u32)
-            .update_conservative(&mut disk_file)?;
-
-        // Open disk, mark it as not initialized
-        let mut gpt_disk = gpt::GptConfig::new()
-            .initialized(false)
-            .writable(true)
-            .logical_block_size(gpt_block_size)
-            .create_from_device(Box::new(&mut disk_file), None)?;
-
-        // Add BIOS boot partition
-        let mut partitions = BTreeMap::new();
-        let mut partition_id = 1;
-        partitions.insert(
-            partition_id,
-            gpt::partition::Partition {
-                part_type_guid: gpt::partition_types::BIOS,
-                part_guid: uuid::Uuid::new_v4(),
-                first_lba: bios_start,
-                last_lba: bios_end,
-                flags: 0, // TODO
-                name: "BIOS".to_string(),
-            },
-        );
-        partition_id += 1;
+    // Format and install partitions other than RedoxFS
+    for partition in &layout {
+        let (start, end) = partition.byte_range(block_size);
+        match partition.filesystem {
+            PartitionFilesystem::Fat32 => {
+                let mut disk_fat = fscommon::StreamSlice::new(&mut disk_file, start, end)?;
+
+                eprintln!(
+                    "Formatting {} partition with size {:#x}",
+                    partition.name,
+                    end - start
+                );
+                // Firmware expects FAT sectors to match the disk logical blocks
+                fatfs::format_volume(
+                    &mut disk_fat,
+                    fatfs::FormatVolumeOptions::new().bytes_per_sector(block_size as u16),
+                )?;
+
+                eprintln!("Opening {} partition", partition.name);
+                let fs =
+                    fatfs::FileSystem::new(&mut disk_fat, disk_option.reproducible.fat_options())?;
+                let root_dir = fs.root_dir();
+
+                if let Some(contents) = &partition.contents {
+                    eprintln!("Copying {contents} to {} partition", partition.name);
+                    copy_dir_to_fat(Path::new(contents), &root_dir)?;
+                }
 
-        // Add EFI boot partition
-        partitions.insert(
-            partition_id,
-            gpt::partition::Partition {
-                part_type_guid: gpt::partition_types::EFI,
-                part_guid: uuid::Uuid::new_v4(),
-                first_lba: efi_start,
-                last_lba: efi_end,
-                flags: 0, // TODO
-                name: "EFI".to_string(),
-            },
-        );
-        partition_id += 1;
+                if partition.is_efi() {
+                    eprintln!("Creating EFI directory");
+                    root_dir.create_dir("EFI")?;
+
+                    eprintln!("Creating EFI/BOOT directory");
+                    let efi_dir = root_dir.open_dir("EFI")?;
+                    efi_dir.create_dir("BOOT")?;
+
+                    eprintln!(
+                        "Writing EFI/BOOT/{} file with size {:#x}",
+                        bootloader_efi_name,
+                        disk_option.bootloader_efi.len()
+                    );
+                    let boot_dir = efi_dir.open_dir("BOOT")?;
+                    let mut file = boot_dir.create_file(bootloader_efi_name)?;
+                    file.truncate()?;
+                    file.write_all(&disk_option.bootloader_efi)?;
+                }
+            }
+            PartitionFilesystem::Raw => {
+                let Some(contents) = &partition.contents else {
+                    continue;
+                };
+                let mut image = fs::File::open(contents)
+                    .with_context(|| format!("failed to open {contents}"))?;
+                let image_size = image.metadata()?.len();
+                if image_size > end - start {
+                    bail!(
+                        "partition {}: {contents} is larger than the partition size {:#x}",
+                        partition.name,
+                        end - start
+                    );
+                }
 
-        // Add RedoxFS partition
-        partitions.insert(
-            partition_id,
-            gpt::partition::Partition {
-                //TODO: Use REDOX_REDOXFS type (needs GPT crate changes)
-                part_type_guid: gpt::partition_types::LINUX_FS,
-                part_guid: uuid::Uuid::new_v4(),
-                first_lba: redoxfs_start,
-                last_lba: redoxfs_end,
-                flags: 0,
-                name: "REDOX".to_string(),
-            },
-        );
+                eprintln!(
+                    "Writing {contents} to {} partition with size {image_size:#x}",
+                    partition.name
+                );
+                let mut disk_raw = fscommon::StreamSlice::new(&mut disk_file, start, end)?;
+                io::copy(&mut image, &mut disk_raw)?;
+            }
+            PartitionFilesystem::Redoxfs | PartitionFilesystem::None => (),
+        }
+    }
 
-        eprintln!("Writing GPT tables: {partitions:#?}");
+    // Format and install RedoxFS partition
+    eprintln!(
+        "Installing to RedoxFS partition with size {:#x}",
+        disk_redoxfs_end - disk_redoxfs_start
+    );
+    let disk_redoxfs = DiskIo(fscommon::StreamSlice::new(
+        disk_file,
+        disk_redoxfs_start,
+        disk_redoxfs_end,
+    )?);
+    let res = with_redoxfs(
+        disk_redoxfs,
+        disk_option.password_opt,
+        &disk_option.reproducible,
+        callback,
+    )?;
+    set_redoxfs_times(disk_path, disk_option, disk_redoxfs_start, disk_redoxfs_end)?;
+
+    // Partition tables are written last, to fit an image cut after a shrunk RedoxFS
+    let mut disk_size = disk_size;
+    if truncate {
+        if let Some(size) = fit_layout_to_redoxfs(disk_path, disk_option, &mut layout, block_size)?
+        {
+            disk_size = size;
+            eprintln!("Truncating disk to {}", format_bytes(disk_size));
+            fs::OpenOptions::new()
+                .write(true)
+                .open(disk_path)?
+                .set_len(disk_size)?;
+        }
+    }
+    let mut disk_file = DiskWrapper::open(disk_path, Some(block_size as usize))?;
+    write_partition_table(&mut disk_file, disk_option, &layout, disk_size, block_size)?;
+    disk_file.flush()?;
 
-        // Initialize GPT table
-        gpt_disk.update_partitions(partitions)?;
+    Ok(res)
+}
 
-        // Write partition layout, returning disk file
-        gpt_disk.write()?;
+/// Wraps a disk image written by [with_whole_disk], created with the given size,
+/// into a hybrid ISO image.
+/// The partition table of the disk is repeated in the system area of the ISO, pointing
+/// into the embedded disk image, so that the ISO also boots when written to a USB drive.
+/// This includes BIOS boot only if the BIOS bootloader fits in the system area, as
+/// it expects its later stages right after the MBR.
+/// BIOS boot from optical media emulates a hard disk, which requires 512-byte blocks.
+pub fn write_iso(
+    iso_path: &Path,
+    disk_path: &Path,
+    disk_size: u64,
+    disk_option: &DiskOption,
+) -> Result<()> {
+    let disk = fs::File::open(disk_path)?;
+    let block_size = disk_option
+        .block_size
+        .map_or(DEFAULT_BLOCK_SIZE as u64, u64::from);
+    let partitions = if disk_option.partitions.is_empty() {
+        PartitionConfig::default_layout(disk_option.efi_partition_size)
+    } else {
+        disk_option.partitions.to_vec()
+    };
+    let mut layout = partition_layout(disk_size, block_size, &partitions)?;
+    if disk_option.shrink {
+        fit_layout_to_redoxfs(disk_path, disk_option, &mut layout, block_size)?;
     }
+    let bios = if disk_option.bootloader_bios.is_empty() {
+        None
+    } else {
+        if block_size != 512 {
+            bail!("BIOS boot from an ISO image requires 512-byte blocks, not {block_size}");
+        }
+        // The first partition with an MBR type, as a GPT disk would list in a hybrid MBR
+        let system_type = layout
+            .iter()
+            .find_map(|partition| mbr_type(partition).ok().flatten())
+            .unwrap_or(REDOXFS_MBR_TYPE);
+        Some(system_type)
+    };
+    let boot = image::iso::IsoBoot {
+        bios,
+        efi: layout
+            .iter()
+            .find(|partition| partition.is_efi())
+            .map(|partition| {
+                let (start, end) = partition.byte_range(block_size);
+                start..end
+            }),
+    };
 
-    // Format and install EFI partition
-    {
-        let disk_efi_start = efi_start * block_size;
-        let disk_efi_end = (efi_end + 1) * block_size;
-        let mut disk_efi =
-            fscommon::StreamSlice::new(&mut disk_file, disk_efi_start, disk_efi_end)?;
-
+    eprintln!("Writing ISO image {}", iso_path.display());
+    let iso_size = image::iso::write(
+        &fs::File::create(iso_path)?,
+        &disk,
+        &boot,
+        disk_option.reproducible.ctime()?.as_secs(),
+    )?;
+
+    let mut iso_file = DiskWrapper::open(iso_path, Some(block_size as usize))?;
+    if disk_option.bootloader_bios.len() as u64 <= image::iso::SYSTEM_AREA_SIZE {
+        eprintln!("Writing bootloader to the ISO system area");
+        iso_file.seek(SeekFrom::Start(0))?;
+        iso_file.write_all(disk_option.bootloader_bios)?;
+    } else if !disk_option.bootloader_bios.is_empty() {
         eprintln!(
-            "Formatting EFI partition with size {:#x}",
-            disk_efi_end - disk_efi_start
+            "BIOS bootloader does not fit in the ISO system area, USB drives boot with UEFI only"
         );
-        fatfs::format_volume(&mut disk_efi, fatfs::FormatVolumeOptions::new())?;
+    }
+    let offset = image::iso::DISK_OFFSET / block_size;
+    let iso_layout: Vec<PartitionPlan> = layout
+        .into_iter()
+        .map(|mut partition| {
+            partition.first_lba += offset;
+            partition.last_lba += offset;
+            partition
+        })
+        .collect();
+    write_partition_table(
+        &mut iso_file,
+        disk_option,
+        &iso_layout,
+        iso_size,
+        block_size,
+    )?;
+    iso_file.flush()?;
+    Ok(())
+}
 
-        eprintln!("Opening EFI partition");
-        let fs = fatfs::FileSystem::new(&mut disk_efi, fatfs::FsOptions::new())?;
+/// Installs RedoxFS into free space of an existing GPT disk, leaving other partitions
+/// untouched, and adds the UEFI bootloader to the existing EFI system partition.
+/// The BIOS bootloader is not written, as it would replace the boot code of the other OS.
+pub fn with_free_space<P, F, T>(
+    disk_path: P,
+    disk_option: &DiskOption,
+    range: Option<[u64; 2]>,
+    callback: F,
+) -> Result<T>
+where
+    P: AsRef<Path>,
+    F: FnOnce(FileSystem<DiskIo<fscommon::StreamSlice<DiskWrapper>>>) -> Result<T>,
+{
+    let bootloader_efi_name = efi_bootloader_name(&get_target())?;
+    // Open disk and read metadata
+    eprintln!("Opening disk {}", disk_path.as_ref().display());
+    let mut disk_file = DiskWrapper::open(
+        disk_path.as_ref(),
+        disk_option.block_size.map(|size| size as usize),
+    )?;
+    let block_size = disk_file.block_size() as u64;
 
-        eprintln!("Creating EFI directory");
-        let root_dir = fs.root_dir();
-        root_dir.create_dir("EFI")?;
+    let (esp, redoxfs) = {
+        let mut gpt_disk = gpt::GptConfig::new()
+            .writable(true)
+            .logical_block_size(gpt_block_size(block_size)?)
+            .open_from_device(Box::new(&mut disk_file))
+            .context("failed to read GPT, alongside requires a GPT disk")?;
+
+        let mut partitions = gpt_disk.partitions().clone();
+        let Some(esp) = partitions
+            .values()
+            .find(|partition| partition.part_type_guid.guid == gpt::partition_types::EFI.guid)
+            .cloned()
+        else {
+            bail!("no EFI system partition found");
+        };
+
+        let (first_lba, last_lba) =
+            free_space_range(&gpt_disk.find_free_sectors(), range, block_size)?;
+        let redoxfs = gpt::partition::Partition {
+            part_type_guid: REDOXFS_PART_TYPE,
+            part_guid: disk_option.reproducible.guid("alongside"),
+            first_lba,
+            last_lba,
+            flags: 0,
+            name: "REDOX".to_string(),
+        };
+        let partition_id = partitions.keys().max().map_or(1, |id| id + 1);
+        partitions.insert(partition_id, redoxfs.clone());
+
+        eprintln!("Adding RedoxFS partition {partition_id}: {redoxfs:#?}");
+        gpt_disk.update_partitions(partitions)?;
+        gpt_disk.write()?;
+
+        (esp, redoxfs)
+    };
 
-        eprintln!("Creating EFI/BOOT directory");
-        let efi_dir = root_dir.open_dir("EFI")?;
-        efi_dir.create_dir("BOOT")?;
+    // Install UEFI bootloader into the existing EFI partition
+    {
+        let mut disk_efi = fscommon::StreamSlice::new(
+            &mut disk_file,
+            esp.first_lba * block_size,
+            (esp.last_lba + 1) * block_size,
+        )?;
+
+        eprintln!("Opening EFI partition {}", esp.name);
+        let fs = fatfs::FileSystem::new(&mut disk_efi, disk_option.reproducible.fat_options())?;
+        let efi_dir = fs.root_dir().create_dir("EFI")?;
 
         eprintln!(
-            "Writing EFI/BOOT/{} file with size {:#x}",
+            "Writing EFI/redox/{} file with size {:#x}",
             bootloader_efi_name,
             disk_option.bootloader_efi.len()
         );
-        let boot_dir = efi_dir.open_dir("BOOT")?;
-        let mut file = boot_dir.create_file(bootloader_efi_name)?;
+        let mut file = efi_dir
+            .create_dir("redox")?
+            .create_file(bootloader_efi_name)?;
         file.truncate()?;
         file.write_all(&disk_option.bootloader_efi)?;
+
+        // Only use the fallback path when no other OS claimed it
+        let boot_dir = efi_dir.create_dir("BOOT")?;
+        if boot_dir.open_file(bootloader_efi_name).is_err() {
+            eprintln!("Writing EFI/BOOT/{} file", bootloader_efi_name);
+            let mut file = boot_dir.create_file(bootloader_efi_name)?;
+            file.truncate()?;
+            file.write_all(&disk_option.bootloader_efi)?;
+        } else {
+            eprintln!(
+                "EFI/BOOT/{} belongs to another OS, add a firmware boot entry for EFI/redox/{} to boot Redox",
+                bootloader_efi_name, bootloader_efi_name
+            );
+        }
     }
 
     // Format and install RedoxFS partition
+    let (disk_redoxfs_start, disk_redoxfs_end) = (
+        redoxfs.first_lba * block_size,
+        (redoxfs.last_lba + 1) * block_size,
+    );
     eprintln!(
         "Installing to RedoxFS partition with size {:#x}",
-        (redoxfs_end - redoxfs_start) * block_size
+        disk_redoxfs_end - disk_redoxfs_start
     );
     let disk_redoxfs = DiskIo(fscommon::StreamSlice::new(
         disk_file,
-        redoxfs_start * block_size,
-        (redoxfs_end + 1) * block_size,
+        disk_redoxfs_start,
+        disk_redoxfs_end,
     )?);
-    with_redoxfs(disk_redoxfs, disk_option.password_opt, callback)
+    with_redoxfs(
+        disk_redoxfs,
+        disk_option.password_opt,
+        &disk_option.reproducible,
+        callback,
+    )
+}
+
+/// Installs RedoxFS into an existing GPT partition, such as one found by [find_gpt_partition].
+/// The partition is formatted whatever its type, see [check_redoxfs_partition]
+pub fn with_gpt_partition<P, F, T>(
+    disk_path: P,
+    partition: &gpt::partition::Partition,
+    disk_option: &DiskOption,
+    callback: F,
+) -> Result<T>
+where
+    P: AsRef<Path>,
+    F: FnOnce(FileSystem<DiskIo<fscommon::StreamSlice<DiskWrapper>>>) -> Result<T>,
+{
+    eprintln!("Opening disk {}", disk_path.as_ref().display());
+    let disk_file = DiskWrapper::open(
+        disk_path.as_ref(),
+        disk_option.block_size.map(|size| size as usize),
+    )?;
+    let block_size = disk_file.block_size() as u64;
+
+    let start = partition.first_lba * block_size;
+    let end = (partition.last_lba + 1) * block_size;
+    if partition.first_lba > partition.last_lba || end > disk_file.size() {
+        bail!(
+            "partition {} ({}) is outside of the disk",
+            partition.name,
+            partition.part_guid
+        );
+    }
+
+    eprintln!(
+        "Installing to partition {} ({}) with size {:#x}",
+        partition.name,
+        partition.part_guid,
+        end - start
+    );
+    let disk_redoxfs = DiskIo(fscommon::StreamSlice::new(disk_file, start, end)?);
+    let res = with_redoxfs(
+        disk_redoxfs,
+        disk_option.password_opt,
+        &disk_option.reproducible,
+        callback,
+    )?;
+    set_redoxfs_times(disk_path.as_ref(), disk_option, start, end)?;
+    Ok(res)
+}
+
+/// GPT header signature, in the second logical block of the disk
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// MBR partition type of the protective entry covering a GPT disk
+const GPT_PROTECTIVE_MBR_TYPE: u8 = 0xEE;
+/// RedoxFS header signature, in the first block of a disk without partitions
+const REDOXFS_SIGNATURE: &[u8] = b"RedoxFS\0";
+
+/// Partition table of an existing disk, None for RedoxFS on the whole disk
+fn existing_partition_scheme(disk_file: &mut DiskWrapper) -> Result<Option<PartitionScheme>> {
+    let block_size = disk_file.block_size();
+    let mut first_blocks = vec![0; 2 * block_size];
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.read_exact(&mut first_blocks)?;
+
+    let mbr = first_blocks[510..512] == [0x55, 0xAA];
+    let protective = first_blocks[446..510]
+        .chunks_exact(16)
+        .any(|entry| entry[4] == GPT_PROTECTIVE_MBR_TYPE);
+    if first_blocks.starts_with(REDOXFS_SIGNATURE) {
+        Ok(None)
+    } else if mbr && !protective {
+        // Without a protective entry, a GPT header is left over from an earlier install
+        Ok(Some(PartitionScheme::Mbr))
+    } else if first_blocks[block_size..].starts_with(GPT_SIGNATURE) {
+        Ok(Some(PartitionScheme::Gpt))
+    } else {
+        bail!("no GPT, MBR or RedoxFS found");
+    }
+}
+
+/// Byte range of the RedoxFS of an existing disk: its RedoxFS partition in a GPT or
+/// MBR partition table, or the whole disk without partitions
+pub(crate) fn existing_redoxfs_range(disk_file: &mut DiskWrapper) -> Result<(u64, u64)> {
+    let disk_size = disk_file.size();
+    let block_size = disk_file.block_size() as u64;
+    match existing_partition_scheme(disk_file)? {
+        None => Ok((0, disk_size)),
+        Some(PartitionScheme::Gpt) => {
+            let gpt_disk = gpt::GptConfig::new()
+                .writable(false)
+                .logical_block_size(gpt_block_size(block_size)?)
+                .open_from_device(Box::new(&mut *disk_file))
+                .context("failed to read GPT")?;
+            let partitions: Vec<_> = gpt_disk
+                .partitions()
+                .values()
+                .filter(|partition| is_redoxfs_part_type(&partition.part_type_guid))
+                .collect();
+            let [partition] = partitions[..] else {
+                bail!("expected one RedoxFS partition, found {}", partitions.len());
+            };
+            Ok((
+                partition.first_lba * block_size,
+                (partition.last_lba + 1) * block_size,
+            ))
+        }
+        Some(PartitionScheme::Mbr) => {
+            let mut table = [0; 64];
+            disk_file.seek(SeekFrom::Start(446))?;
+            disk_file.read_exact(&mut table)?;
+            let entries: Vec<&[u8]> = table
+                .chunks_exact(16)
+                .filter(|entry| entry[4] == REDOXFS_MBR_TYPE)
+                .collect();
+            let [entry] = entries[..] else {
+                bail!(
+                    "expected one RedoxFS MBR partition, found {}",
+                    entries.len()
+                );
+            };
+            let first_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+            let blocks = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+            Ok((first_lba * block_size, (first_lba + blocks) * block_size))
+        }
+    }
+}
+
+/// Opens the RedoxFS of an existing disk, found by [existing_redoxfs_range], keeping its files.
+/// Partitions and bootloaders are left untouched.
+pub fn with_existing_redoxfs<P, F, T>(
+    disk_path: P,
+    disk_option: &DiskOption,
+    callback: F,
+) -> Result<T>
+where
+    P: AsRef<Path>,
+    F: FnOnce(FileSystem<DiskIo<fscommon::StreamSlice<DiskWrapper>>>) -> Result<T>,
+{
+    eprintln!("Opening disk {}", disk_path.as_ref().display());
+    let mut disk_file = DiskWrapper::open(
+        disk_path.as_ref(),
+        disk_option.block_size.map(|size| size as usize),
+    )?;
+    let (start, end) = existing_redoxfs_range(&mut disk_file)
+        .with_context(|| format!("{} has no RedoxFS to update", disk_path.as_ref().display()))?;
+
+    eprintln!("Updating RedoxFS with size {:#x}", end - start);
+    let fs = FileSystem::open(
+        DiskIo(fscommon::StreamSlice::new(disk_file, start, end)?),
+        disk_option.password_opt,
+        None,
+        false,
+    )?;
+    callback(fs)
+}
+
+/// Grows a disk written by the installer, keeping its GUIDs and files: the RedoxFS in its
+/// last partition, or on the whole disk without partitions, is grown to the end of the disk.
+/// Raw image files are first extended to size, other disks must already have been grown.
+pub fn resize(
+    disk_path: impl AsRef<Path>,
+    size: Option<u64>,
+    block_size: Option<u32>,
+    password_opt: Option<&[u8]>,
+) -> Result<()> {
+    let disk_path = disk_path.as_ref();
+    let image_block_size = block_size.map(|size| size as usize);
+    let old_size = DiskWrapper::open(disk_path, image_block_size)?.size();
+    if let Some(size) = size {
+        if size < old_size {
+            bail!(
+                "{} is larger than {}, shrinking is not supported",
+                disk_path.display(),
+                format_bytes(size)
+            );
+        }
+        if size > old_size {
+            if !is_raw_image_file(disk_path)? {
+                bail!(
+                    "only raw image files can be extended, grow {} with its own tools and resize it without a size",
+                    disk_path.display()
+                );
+            }
+            eprintln!(
+                "Extending {} from {} to {}",
+                disk_path.display(),
+                format_bytes(old_size),
+                format_bytes(size)
+            );
+            fs::OpenOptions::new()
+                .write(true)
+                .open(disk_path)?
+                .set_len(size)?;
+        }
+    }
+
+    let mut disk_file = DiskWrapper::open(disk_path, image_block_size)?;
+    let disk_size = disk_file.size();
+    let block_size = disk_file.block_size() as u64;
+    let scheme = existing_partition_scheme(&mut disk_file)
+        .with_context(|| format!("{} cannot be resized", disk_path.display()))?;
+    let (start, end) = match scheme {
+        None => (0, disk_size),
+        Some(PartitionScheme::Gpt) => {
+            grow_gpt_partition(&mut disk_file, old_size, disk_size, block_size)?
+        }
+        Some(PartitionScheme::Mbr) => grow_mbr_partition(&mut disk_file, disk_size, block_size)?,
+    };
+    disk_file.flush()?;
+
+    let mut fs = FileSystem::open(
+        DiskIo(fscommon::StreamSlice::new(disk_file, start, end)?),
+        password_opt,
+        None,
+        false,
+    )?;
+    let old_fs_size = fs.header.size();
+    let fs_size = grow_redoxfs(&mut fs, end - start)?;
+    if fs_size > old_fs_size {
+        eprintln!(
+            "Grew RedoxFS from {} to {}",
+            format_bytes(old_fs_size),
+            format_bytes(fs_size)
+        );
+    } else {
+        eprintln!("RedoxFS already fills the disk");
+    }
+    Ok(())
+}
+
+/// Rewrites the GPT tables of a grown disk with the same GUIDs, growing its last partition,
+/// which must be RedoxFS, to the end of the disk. Returns the byte range of the partition
+fn grow_gpt_partition(
+    disk_file: &mut DiskWrapper,
+    old_size: u64,
+    disk_size: u64,
+    block_size: u64,
+) -> Result<(u64, u64)> {
+    let (disk_guid, mut partitions) = {
+        let gpt_disk = gpt::GptConfig::new()
+            .writable(false)
+            .logical_block_size(gpt_block_size(block_size)?)
+            .open_from_device(Box::new(&mut *disk_file))
+            .context("failed to read GPT")?;
+        (*gpt_disk.guid(), gpt_disk.partitions().clone())
+    };
+    let Some(partition) = partitions
+        .values_mut()
+        .max_by_key(|partition| partition.last_lba)
+    else {
+        bail!("GPT has no partitions");
+    };
+    if !is_redoxfs_part_type(&partition.part_type_guid) {
+        bail!("last partition {} is not RedoxFS", partition.name);
+    }
+
+    // Align the end to 1 MiB like new partitions, before the backup GPT table
+    let end = (disk_size - block_size - 128 * 128) / (1024 * 1024) * (1024 * 1024);
+    if end / block_size > partition.last_lba + 1 {
+        partition.last_lba = end / block_size - 1;
+        eprintln!(
+            "Growing partition {} to {}",
+            partition.name,
+            format_bytes(end - partition.first_lba * block_size)
+        );
+    }
+    let range = (
+        partition.first_lba * block_size,
+        (partition.last_lba + 1) * block_size,
+    );
+    write_gpt_partitions(disk_file, partitions, disk_guid, disk_size, block_size)?;
+
+    // Clear the previous backup GPT header, now inside the RedoxFS partition
+    if old_size < disk_size {
+        let old_backup = (old_size / block_size - 1) * block_size;
+        let mut header = vec![0; block_size as usize];
+        disk_file.seek(SeekFrom::Start(old_backup))?;
+        disk_file.read_exact(&mut header)?;
+        if header.starts_with(GPT_SIGNATURE) {
+            header.fill(0);
+            disk_file.seek(SeekFrom::Start(old_backup))?;
+            disk_file.write_all(&header)?;
+        }
+    }
+    Ok(range)
+}
+
+/// Grows the last partition of an MBR disk, which must be RedoxFS, to the end of the disk.
+/// Returns the byte range of the partition
+fn grow_mbr_partition(
+    disk_file: &mut DiskWrapper,
+    disk_size: u64,
+    block_size: u64,
+) -> Result<(u64, u64)> {
+    let mut table = [0; 64];
+    disk_file.seek(SeekFrom::Start(446))?;
+    disk_file.read_exact(&mut table)?;
+    let read_u32 = |entry: &[u8], offset: usize| {
+        u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap()) as u64
+    };
+    let Some(entry) = table
+        .chunks_exact_mut(16)
+        .filter(|entry| entry[4] != 0)
+        .max_by_key(|entry| read_u32(entry, 8) + read_u32(entry, 12))
+    else {
+        bail!("MBR has no partitions");
+    };
+    if entry[4] != REDOXFS_MBR_TYPE {
+        bail!("last MBR partition has type {:#04x}, not RedoxFS", entry[4]);
+    }
+
+    // Align the end to 1 MiB like new partitions, within the blocks MBR can address
+    let first_lba = read_u32(entry, 8);
+    let end_lba = (disk_size / (1024 * 1024) * (1024 * 1024) / block_size).min(u32::MAX as u64);
+    let grown = end_lba > first_lba + read_u32(entry, 12);
+    if grown {
+        let blocks = (end_lba - first_lba) as u32;
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+        eprintln!(
+            "Growing MBR partition to {}",
+            format_bytes(blocks as u64 * block_size)
+        );
+    }
+    let range = (
+        first_lba * block_size,
+        (first_lba + read_u32(entry, 12)) * block_size,
+    );
+    if grown {
+        disk_file.seek(SeekFrom::Start(446))?;
+        disk_file.write_all(&table)?;
+    }
+    Ok(range)
 }
 
 #[cfg(not(target_os = "redox"))]
@@ -799,33 +1765,181 @@ pub fn try_fast_install<D: redoxfs::Disk, F: FnMut(u64, u64)>(
     Ok(true)
 }
 
-fn install_inner(config: Config, output: &Path) -> Result<()> {
+fn check_config(config: &Config) -> Result<()> {
+    let diagnostics = config.validate();
+    for diagnostic in &diagnostics {
+        eprintln!("{diagnostic}");
+    }
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        bail!("Refusing to install an invalid config");
+    }
+    Ok(())
+}
+
+/// Installs the config into a newly formatted RedoxFS, or an existing one to update.
+/// With shrink, a filesystem written with RedoxFS AR is cut down to its content,
+/// for a raw image that is then cut after it.
+fn install_redoxfs<D>(
+    config: Config,
+    fs: FileSystem<D>,
+    source: &dyn PackageSource,
+    shrink: bool,
+) -> Result<()>
+where
+    D: Disk + Send + 'static,
+{
+    if config.general.no_mount.unwrap_or(false) {
+        // Files kept by an update keep their times
+        let reproducible = if config.general.update.unwrap_or(false) {
+            Reproducible::default()
+        } else {
+            Reproducible::new(config.general.reproducible_seed.as_deref())?
+        };
+        with_redoxfs_ar(fs, None, &reproducible, shrink, move |mount_path| {
+            install_dir(config, mount_path, source)
+        })
+    } else {
+        with_redoxfs_mount(fs, None, move |mount_path| {
+            install_dir(config, mount_path, source)
+        })
+    }
+}
+
+fn install_partition_inner(
+    config: Config,
+    selector: &PartitionSelector,
+    disks: &[PathBuf],
+    source: &dyn PackageSource,
+) -> Result<()> {
+    let (disk_path, partition) = find_gpt_partition(selector, disks, config.general.block_size)?;
+    check_redoxfs_partition(&partition, config.general.force.unwrap_or(false))?;
+    println!(
+        "Installing to {selector} on {}:\n{}",
+        disk_path.display(),
+        config
+    );
+    check_config(&config)?;
+
+    // Boot partitions are left as they are
+    let password_opt = config.general.encrypt_disk.clone();
+    let reproducible = Reproducible::new(config.general.reproducible_seed.as_deref())?;
+    let disk_option = DiskOption {
+        bootloader_bios: &[],
+        bootloader_efi: &[],
+        password_opt: password_opt.as_ref().map(|p| p.as_bytes()),
+        efi_partition_size: None,
+        skip_partitions: true,
+        partition_scheme: PartitionScheme::Gpt,
+        block_size: config.general.block_size,
+        partitions: &[],
+        reproducible,
+        shrink: false,
+    };
+    with_gpt_partition(&disk_path, &partition, &disk_option, move |fs| {
+        install_redoxfs(config, fs, source, false)
+    })
+}
+
+fn install_inner(mut config: Config, output: &Path, source: &dyn PackageSource) -> Result<()> {
+    let selector = output.to_str().map(PartitionSelector::from_target);
+    if let Some(Some(selector)) = selector.transpose()? {
+        return install_partition_inner(config, &selector, &[], source);
+    }
     println!("Installing to {}:\n{}", output.display(), config);
-    let cookbook = config.general.cookbook.clone();
-    let cookbook = cookbook.as_ref().map(|p| p.as_str());
+    check_config(&config)?;
     if output.is_dir() {
-        install_dir(config, output, cookbook)
+        install_dir(config, output, source)
     } else {
-        if !output.is_file() {
-            let fs_size = config.general.filesystem_size.unwrap_or(0) as u64;
-            // arbitrary size approximately fit just for initfs
-            if fs_size < 32 {
-                bail!("Refusing to create image disk less than 32 MB");
+        let alongside = config.general.alongside.unwrap_or(false);
+        if alongside && !output.exists() {
+            bail!(
+                "{} must be an existing GPT disk to install alongside",
+                output.display()
+            );
+        }
+        let update = config.general.update.unwrap_or(false);
+        if update && !output.exists() {
+            bail!("{} must be an existing disk to update", output.display());
+        }
+        let reproducible = Reproducible::new(config.general.reproducible_seed.as_deref())?;
+        let format = config
+            .general
+            .image_format
+            .unwrap_or_else(|| ImageFormat::from_path(output));
+        // ISO images are always written anew, around a raw disk image built next to them
+        let iso = format == ImageFormat::Iso;
+        if iso {
+            if update {
+                bail!("ISO images are written anew and cannot be updated");
+            }
+            if alongside || config.general.skip_partitions.unwrap_or(false) {
+                bail!("ISO images need their own partitions, without alongside or skip_partitions");
+            }
+            if config.general.block_size.is_some_and(|size| size != 512) {
+                bail!("ISO images boot as a disk of 512-byte blocks");
+            }
+            // Optical media are read-only, so ISO images boot into memory
+            config.general.live_disk = Some(true);
+        }
+        let disk_path = if iso {
+            let mut disk_path = output.as_os_str().to_owned();
+            disk_path.push(".img");
+            PathBuf::from(disk_path)
+        } else {
+            output.to_path_buf()
+        };
+        // Existing disks keep their size, "auto" is only computed for new images
+        let mut fs_size = 0;
+        let mut created = false;
+        if output.is_file() && !iso {
+            // Existing images keep their format
+            let format = image::detect(&fs::File::open(output)?)?;
+            if let Some(image_format) = config.general.image_format {
+                if image_format != format {
+                    bail!(
+                        "{} is an existing {format} image, not {image_format}",
+                        output.display()
+                    );
+                }
+            }
+        } else if !alongside && !update {
+            fs_size = filesystem_size(&config, source)? as u64;
+            if fs_size < MIN_FILESYSTEM_SIZE as u64 {
+                bail!("Refusing to create image disk less than {MIN_FILESYSTEM_SIZE} MB");
             }
             eprintln!(
-                "Creating a new file to {} with size {} MB",
+                "Creating a new {format} image to {} with size {} MB",
                 output.display(),
                 fs_size
             );
-            let file = fs::File::create(output)?;
-            file.set_len(fs_size * 1024 * 1024)?;
+            image::create(
+                &disk_path,
+                if iso { ImageFormat::Raw } else { format },
+                fs_size * 1024 * 1024,
+                reproducible.guid("image"),
+            )?;
+            created = true;
         }
         let live = config.general.live_disk.unwrap_or(false);
+        // Live disks are loaded into memory whole, so a new raw image of one written with
+        // RedoxFS AR is cut down to its content. Other disks keep their free space
+        let shrink = created
+            && live
+            && config.general.no_mount.unwrap_or(false)
+            && is_raw_image_file(&disk_path)?;
         let password_opt = config.general.encrypt_disk.clone();
         let password_opt = password_opt.as_ref().map(|p| p.as_bytes());
-        let (bootloader_bios, bootloader_efi) = fetch_bootloaders(&config, cookbook, live)?;
+        let partitions = config.partitions.clone();
+        // Updates leave the bootloaders as they are
+        let (bootloader_bios, bootloader_efi) = if update {
+            (Vec::new(), Vec::new())
+        } else {
+            fetch_bootloaders(&config, source, live)?
+        };
         if let Some(write_bootloader) = &config.general.write_bootloader {
-            std::fs::write(write_bootloader, &bootloader_efi)?;
+            if !update {
+                std::fs::write(write_bootloader, &bootloader_efi)?;
+            }
         }
         let disk_option = DiskOption {
             bootloader_bios: &bootloader_bios,
@@ -833,23 +1947,118 @@ fn install_inner(config: Config, output: &Path) -> Result<()> {
             password_opt: password_opt,
             efi_partition_size: config.general.efi_partition_size,
             skip_partitions: config.general.skip_partitions.unwrap_or(false),
+            partition_scheme: config.general.partition_scheme.unwrap_or_default(),
+            block_size: config.general.block_size,
+            partitions: &partitions,
+            reproducible,
+            shrink,
         };
-        with_whole_disk(output, &disk_option, move |fs| {
-            if config.general.no_mount.unwrap_or(false) {
-                with_redoxfs_ar(fs, None, move |mount_path| {
-                    install_dir(config, mount_path, cookbook)
-                })
-            } else {
-                with_redoxfs_mount(fs, None, move |mount_path| {
-                    install_dir(config, mount_path, cookbook)
-                })
-            }
-        })
+        let alongside_range = config.general.alongside_range;
+        let install = move |fs| install_redoxfs(config, fs, source, shrink);
+        if update {
+            with_existing_redoxfs(output, &disk_option, install)
+        } else if alongside {
+            with_free_space(output, &disk_option, alongside_range, install)
+        } else if iso {
+            with_whole_disk(&disk_path, &disk_option, install)?;
+            write_iso(output, &disk_path, fs_size * 1024 * 1024, &disk_option)?;
+            fs::remove_file(&disk_path)?;
+            Ok(())
+        } else {
+            with_whole_disk(output, &disk_option, install)
+        }
     }
 }
 
 /// Install RedoxFS into a new disk file, or a sysroot directory.
 /// This function assumes all interactive prompts resolved by the caller.
 pub fn install(config: Config, output: impl AsRef<Path>) -> Result<()> {
-    install_inner(config, output.as_ref())
+    let source = package_source(&config)?;
+    install_inner(config, output.as_ref(), source.as_ref())
+}
+
+/// Same as [install], with packages provided by the caller instead of the config
+pub fn install_with_source(
+    config: Config,
+    output: impl AsRef<Path>,
+    source: &dyn PackageSource,
+) -> Result<()> {
+    install_inner(config, output.as_ref(), source)
+}
+
+/// Install RedoxFS into an existing GPT partition, found on the given disks
+/// or on every disk of the system if none are given
+pub fn install_partition(
+    config: Config,
+    selector: &PartitionSelector,
+    disks: &[PathBuf],
+) -> Result<()> {
+    let source = package_source(&config)?;
+    install_partition_inner(config, selector, disks, source.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Opens the RedoxFS of a whole-disk image
+    fn open_redoxfs(path: &Path) -> FileSystem<DiskIo<fscommon::StreamSlice<DiskWrapper>>> {
+        let disk_file = DiskWrapper::open(path, None).unwrap();
+        let size = disk_file.size();
+        let disk = DiskIo(fscommon::StreamSlice::new(disk_file, 0, size).unwrap());
+        FileSystem::open(disk, None, None, false).unwrap()
+    }
+
+    #[test]
+    fn shrink_then_grow() {
+        let dir = env::temp_dir().join(format!("redox_installer_resize_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("disk.img");
+        fs::File::create(&path).unwrap().set_len(SIZE).unwrap();
+
+        // Written and shrunk like a new live image
+        let disk_file = DiskWrapper::open(&path, None).unwrap();
+        let disk = DiskIo(fscommon::StreamSlice::new(disk_file, 0, SIZE).unwrap());
+        let reproducible = Reproducible::default();
+        let root = dir.join("root");
+        with_redoxfs(disk, None, &reproducible, |fs| {
+            with_redoxfs_ar(fs, Some(&root), &reproducible, true, |root| {
+                fs::create_dir_all(root)?;
+                fs::write(root.join("data"), vec![0x5A; 1024 * 1024])?;
+                Ok(())
+            })
+        })
+        .unwrap();
+
+        let shrunk = open_redoxfs(&path).header.size();
+        assert!(shrunk >= 1024 * 1024, "{shrunk}");
+        assert!(shrunk < SIZE / 2, "{shrunk}");
+        assert_eq!(shrunk % BLOCK_SIZE, 0);
+
+        // Cut like a new raw image, then grown back
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(shrunk)
+            .unwrap();
+        assert!(resize(&path, Some(shrunk - BLOCK_SIZE), None, None).is_err());
+        resize(&path, Some(SIZE), None, None).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), SIZE);
+        let fs = open_redoxfs(&path);
+        assert_eq!(fs.header.size(), SIZE);
+        // The added blocks are free
+        assert!(fs.allocator().free() * BLOCK_SIZE >= SIZE - shrunk);
+        drop(fs);
+
+        // Growing again keeps the size
+        resize(&path, None, None, None).unwrap();
+        assert_eq!(open_redoxfs(&path).header.size(), SIZE);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
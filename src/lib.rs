@@ -1,15 +1,60 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "compress")]
+mod compress;
 mod config;
 #[cfg(feature = "installer")]
 mod disk_wrapper;
 #[cfg(feature = "installer")]
+mod image;
+#[cfg(feature = "installer")]
 mod installer;
 #[cfg(feature = "installer")]
+mod package_cache;
+#[cfg(feature = "installer")]
+mod package_source;
+#[cfg(feature = "installer")]
+mod partition;
+#[cfg(feature = "installer")]
+mod plan;
+#[cfg(feature = "installer")]
+mod reproducible;
+#[cfg(feature = "installer")]
+mod target;
+#[cfg(feature = "compress")]
+pub use crate::compress::{compress, Compressed, Compression};
+#[cfg(feature = "installer")]
 pub use crate::installer::*;
+#[cfg(feature = "installer")]
+pub use crate::package_cache::PackageCache;
+#[cfg(feature = "installer")]
+pub use crate::package_source::{
+    package_source, CookbookSource, PackageDirSource, PackageSource, RemoteSource,
+};
+#[cfg(feature = "installer")]
+pub use crate::partition::{
+    is_redoxfs_part_type, partition_layout, redoxfs_partition, PartitionPlan, REDOXFS_PART_TYPE,
+};
+#[cfg(feature = "installer")]
+pub use crate::plan::{
+    install_plan, DiskPlan, EfiFilePlan, FilePlan, GroupPlan, InstallPlan, UserPlan,
+};
+#[cfg(feature = "installer")]
+pub use crate::reproducible::Reproducible;
+#[cfg(feature = "installer")]
+pub use crate::target::{
+    check_redoxfs_partition, find_gpt_partition, gpt_partitions, PartitionSelector,
+};
 
 pub use crate::config::file::format_bytes;
 pub use crate::config::file::FileConfig;
+pub use crate::config::general::{FilesystemSize, ImageFormat};
+pub use crate::config::lock::{LockFile, LockedPackage};
 pub use crate::config::package::PackageConfig;
+pub use crate::config::partition::{
+    PartitionConfig, PartitionFilesystem, PartitionScheme, PartitionSize,
+};
+pub use crate::config::repository::{RepositoryConfig, REDOX_REPO_URL};
+pub use crate::config::validate::{Diagnostic, Severity};
 pub use crate::config::Config;
@@ -0,0 +1,304 @@
+use anyhow::{bail, Context, Result};
+use pkg::net_backend::DownloadBackend;
+use pkg::Library;
+
+use crate::config::lock::LockedPackage;
+use crate::installer::get_target;
+use crate::package_source::{blake3_file, extract_pkgar, PackageToml};
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+};
+
+/// Content-addressed cache of packages downloaded from remote repos.
+/// Package archives are stored by their blake3 hash, so that they are
+/// downloaded once for every image using them, along with the URL of
+/// the repo that served them to find its key.
+pub struct PackageCache {
+    dir: PathBuf,
+    cache_only: bool,
+}
+
+impl PackageCache {
+    pub fn new(dir: impl Into<PathBuf>, cache_only: bool) -> Self {
+        Self {
+            dir: dir.into(),
+            cache_only,
+        }
+    }
+
+    /// $XDG_CACHE_HOME/redox_installer, or ~/.cache/redox_installer
+    pub fn default_dir() -> Option<PathBuf> {
+        let cache_home = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(cache_home.join("redox_installer"))
+    }
+
+    /// Downloads a file from the first repo that has it, returning the repo URL.
+    /// With a blake3 hash, downloads with another hash are refused before they are cached
+    fn download(
+        &self,
+        urls: &[String],
+        remote_path: &str,
+        local_path: &Path,
+        blake3: Option<&str>,
+    ) -> Result<String> {
+        if self.cache_only {
+            bail!("{remote_path} is not cached in {}", self.dir.display());
+        }
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+
+        let callback = Rc::new(RefCell::new(pkg::callback::IndicatifCallback::new()));
+        let download_backend = pkg::net_backend::DefaultNetBackend::new()?;
+        // Download next to the destination, so an interrupted download is never used
+        let partial_path = local_path.with_extension(format!("partial{}", process::id()));
+        let mut mismatch = None;
+        for url in urls {
+            let remote = format!("{url}/{remote_path}");
+            if download_backend
+                .download(&remote, &partial_path, callback.clone())
+                .is_ok()
+            {
+                if let Some(blake3) = blake3 {
+                    let found = blake3_file(&partial_path)?;
+                    if found != blake3 {
+                        // Another repo may still serve this version
+                        mismatch = Some(format!("{remote} has hash {found}, not {blake3}"));
+                        continue;
+                    }
+                }
+                fs::rename(&partial_path, local_path)?;
+                return Ok(url.clone());
+            }
+        }
+        let _ = fs::remove_file(&partial_path);
+        match mismatch {
+            Some(mismatch) => bail!("{mismatch}"),
+            None => bail!("{remote_path} not found in {}", urls.join(" ")),
+        }
+    }
+
+    fn toml_path(&self, name: &str) -> PathBuf {
+        self.dir
+            .join("toml")
+            .join(get_target())
+            .join(format!("{name}.toml"))
+    }
+
+    fn pkgar_path(&self, blake3: &str) -> PathBuf {
+        self.dir.join("pkgar").join(format!("{blake3}.pkgar"))
+    }
+
+    fn key_path(&self, url: &str) -> PathBuf {
+        let name: String = url
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        self.dir.join("keys").join(name).join("id_ed25519.pub.toml")
+    }
+
+    /// Returns the TOML metadata of a package, refreshed from the repos unless cache only
+    pub fn package_toml(&self, urls: &[String], name: &str) -> Result<String> {
+        Ok(self.fetch_toml(urls, name)?.0)
+    }
+
+    /// Same as [PackageCache::package_toml], with the URL of the repo that served it
+    /// unless cache only
+    fn fetch_toml(&self, urls: &[String], name: &str) -> Result<(String, Option<String>)> {
+        let path = self.toml_path(name);
+        let url = if self.cache_only {
+            None
+        } else {
+            let remote_path = format!("{}/{name}.toml", get_target());
+            Some(self.download(urls, &remote_path, &path, None)?)
+        };
+        let package_toml = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok((package_toml, url))
+    }
+
+    /// Returns the cached archive with a blake3 hash and the URL of the repo that served it,
+    /// downloading it from the first repo serving a package archive with this hash otherwise
+    fn pkgar(&self, urls: &[String], name: &str, blake3: &str) -> Result<(PathBuf, String)> {
+        let pkgar_path = self.pkgar_path(blake3);
+        let repo_path = pkgar_path.with_extension("repo");
+        if pkgar_path.is_file() && repo_path.is_file() {
+            let url = fs::read_to_string(&repo_path)?.trim().to_string();
+            return Ok((pkgar_path, url));
+        }
+        let remote_path = format!("{}/{name}.pkgar", get_target());
+        let url = self
+            .download(urls, &remote_path, &pkgar_path, Some(blake3))
+            .with_context(|| format!("package {name} with hash {blake3} is not available"))?;
+        fs::write(&repo_path, &url)?;
+        Ok((pkgar_path, url))
+    }
+
+    /// Returns the trusted public key of a repo, or the key it serves
+    fn repo_key(&self, url: &str, public_keys: &BTreeMap<String, PathBuf>) -> Result<PathBuf> {
+        if let Some(public_key) = public_keys.get(url) {
+            return Ok(public_key.clone());
+        }
+        let key_path = self.key_path(url);
+        if !key_path.is_file() {
+            self.download(&[url.to_string()], "id_ed25519.pub.toml", &key_path, None)?;
+        }
+        Ok(key_path)
+    }
+
+    /// Installs locked packages by their hash, from the cache or from the repos that still
+    /// serve them. Repo metadata is not read, so the packages of an old lockfile install
+    /// for as long as they are cached. Each archive is verified with the key of its repo.
+    pub fn install_locked(
+        &self,
+        urls: &[String],
+        public_keys: &BTreeMap<String, PathBuf>,
+        packages: &BTreeMap<String, LockedPackage>,
+        dest: &Path,
+    ) -> Result<()> {
+        for (name, locked) in packages {
+            let (pkgar_path, url) = self.pkgar(urls, name, &locked.blake3)?;
+            let key_path = self.repo_key(&url, public_keys)?;
+            // Hashed again right before extracting, as the cache may have been changed
+            let blake3 = blake3_file(&pkgar_path)?;
+            if blake3 != locked.blake3 {
+                bail!(
+                    "package {name}: {} has hash {blake3}, differing from locked hash {} ({})",
+                    pkgar_path.display(),
+                    locked.blake3,
+                    locked.version
+                );
+            }
+            extract_pkgar(&pkgar_path, &key_path, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Installs packages and their dependencies, downloading only the archives not yet cached.
+    /// Packages are installed together with the others signed by the key of their repo
+    pub fn install(
+        &self,
+        urls: &[String],
+        public_keys: &BTreeMap<String, PathBuf>,
+        packages: &[String],
+        dest: &Path,
+    ) -> Result<()> {
+        let groups = self.resolve(urls, public_keys, packages)?;
+        for (i, (key_path, group)) in groups.iter().enumerate() {
+            // Stage a local repo linking to the cached archives, so pkg can install it
+            let staging_dir = self.dir.join(format!("staging_{}_{i}", process::id()));
+            if staging_dir.exists() {
+                fs::remove_dir_all(&staging_dir)?;
+            }
+
+            let res = stage(key_path, group, &staging_dir).and_then(|()| {
+                let callback = pkg::callback::PlainCallback::new();
+                let mut library = Library::new_local(
+                    staging_dir.clone(),
+                    staging_dir.clone(),
+                    dest.to_path_buf(),
+                    &get_target(),
+                    Rc::new(RefCell::new(callback)),
+                )?;
+                let packages: Vec<&String> = group.keys().collect();
+                let packages = pkg::PackageName::from_list(packages)?;
+                library.install(packages)?;
+                library.apply()?;
+                Ok(())
+            });
+
+            fs::remove_dir_all(&staging_dir)?;
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Resolves packages and their dependencies to their metadata and cached archives,
+    /// downloading the archives that are not cached yet. Packages are grouped by the key
+    /// of the repo that served them, a trusted public key replacing the key it serves
+    fn resolve(
+        &self,
+        urls: &[String],
+        public_keys: &BTreeMap<String, PathBuf>,
+        packages: &[String],
+    ) -> Result<BTreeMap<PathBuf, BTreeMap<String, StagedPackage>>> {
+        let mut groups: BTreeMap<PathBuf, BTreeMap<String, StagedPackage>> = BTreeMap::new();
+        let mut resolved = BTreeSet::new();
+        let mut queue = packages.to_vec();
+        while let Some(name) = queue.pop() {
+            if !resolved.insert(name.clone()) {
+                continue;
+            }
+
+            let (package_toml, toml_url) = self.fetch_toml(urls, &name)?;
+            let package: PackageToml = toml::from_str(&package_toml)
+                .with_context(|| format!("failed to decode metadata of package {name}"))?;
+            if package.blake3.is_empty() {
+                bail!("package {name} has no blake3 hash and cannot be cached");
+            }
+
+            // The archive must come from the repo that served its metadata
+            let toml_urls = toml_url.map_or_else(|| urls.to_vec(), |url| vec![url]);
+            let (pkgar_path, url) = self.pkgar(&toml_urls, &name, &package.blake3)?;
+            let key_path = self.repo_key(&url, public_keys)?;
+            queue.extend(package.depends);
+            groups.entry(key_path).or_default().insert(
+                name,
+                StagedPackage {
+                    package_toml,
+                    pkgar_path,
+                },
+            );
+        }
+        Ok(groups)
+    }
+}
+
+/// Metadata and cached archive of a package to install
+struct StagedPackage {
+    package_toml: String,
+    pkgar_path: PathBuf,
+}
+
+/// Links packages signed by one key and the key into a local repo. Dependencies
+/// from other repos are left out of the metadata, as they are installed with their own key
+fn stage(
+    key_path: &Path,
+    packages: &BTreeMap<String, StagedPackage>,
+    staging_dir: &Path,
+) -> Result<()> {
+    let repo_dir = staging_dir.join(get_target());
+    fs::create_dir_all(&repo_dir)
+        .with_context(|| format!("failed to create {}", repo_dir.display()))?;
+
+    for (name, package) in packages {
+        let mut package_toml: toml::Table = toml::from_str(&package.package_toml)
+            .with_context(|| format!("failed to decode metadata of package {name}"))?;
+        if let Some(toml::Value::Array(depends)) = package_toml.get_mut("depends") {
+            depends.retain(|depend| depend.as_str().is_some_and(|d| packages.contains_key(d)));
+        }
+        fs::write(
+            repo_dir.join(format!("{name}.toml")),
+            toml::to_string(&package_toml)?,
+        )?;
+
+        let staged_pkgar = repo_dir.join(format!("{name}.pkgar"));
+        if fs::hard_link(&package.pkgar_path, &staged_pkgar).is_err() {
+            fs::copy(&package.pkgar_path, &staged_pkgar)?;
+        }
+    }
+
+    fs::copy(key_path, staging_dir.join("id_ed25519.pub.toml"))
+        .with_context(|| format!("failed to read {}", key_path.display()))?;
+    Ok(())
+}
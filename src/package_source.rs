@@ -0,0 +1,463 @@
+use anyhow::{anyhow, bail, Context, Result};
+use pkg::net_backend::DownloadBackend;
+use pkg::Library;
+use pkgar_core::PackageSrc;
+
+use crate::config::lock::{LockFile, LockedPackage};
+use crate::config::Config;
+use crate::installer::get_target;
+use crate::package_cache::PackageCache;
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// Provides the packages of an install, such as a cookbook or a remote repo.
+/// Embedding crates can implement it to add caching or test fixtures.
+pub trait PackageSource {
+    /// Describes where packages come from, recorded in lockfiles
+    fn source(&self) -> String;
+
+    /// Returns the TOML metadata of a package
+    fn package_toml(&self, name: &str) -> Result<String>;
+
+    /// Installs packages and their dependencies into dest
+    fn install(&self, packages: &[String], dest: &Path) -> Result<()>;
+
+    /// Same as [PackageSource::install], with the dependencies and blake3 hashes of the
+    /// lockfile. Any package archive with another hash is refused before extracting it
+    fn install_locked(
+        &self,
+        _packages: &[String],
+        _lockfile: &LockFile,
+        _dest: &Path,
+    ) -> Result<()> {
+        bail!("{} cannot check packages against a lockfile", self.source());
+    }
+
+    /// Installs a pkgar file from the build host into dest
+    fn install_pkgar(&self, archive: &Path, dest: &Path) -> Result<()> {
+        extract_pkgar(
+            archive,
+            &archive.with_file_name("id_ed25519.pub.toml"),
+            dest,
+        )
+    }
+
+    /// Returns the size of the files of a package once installed, when its metadata
+    /// does not list it. Sources with local pkgar files read it from their entries
+    fn package_size(&self, name: &str) -> Result<u64> {
+        bail!("package {name}: metadata has no storage_size, set filesystem_size in MB instead");
+    }
+
+    /// Returns the size of the files of a pkgar file from the build host
+    fn pkgar_size(&self, archive: &Path) -> Result<u64> {
+        pkgar_size(archive, &archive.with_file_name("id_ed25519.pub.toml"))
+    }
+}
+
+/// Fields of the package TOML metadata used by the installer
+#[derive(Deserialize)]
+pub(crate) struct PackageToml {
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub blake3: String,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// Size of the installed files, missing from older repos
+    #[serde(default)]
+    pub storage_size: u64,
+}
+
+pub(crate) fn package_meta(source: &dyn PackageSource, name: &str) -> Result<PackageToml> {
+    toml::from_str(&source.package_toml(name)?)
+        .with_context(|| format!("failed to decode metadata of package {name}"))
+}
+
+/// Resolves packages and their dependencies, with their metadata
+fn resolve_packages(
+    source: &dyn PackageSource,
+    packages: &[String],
+) -> Result<BTreeMap<String, PackageToml>> {
+    let mut resolved = BTreeMap::new();
+    let mut queue = packages.to_vec();
+    while let Some(name) = queue.pop() {
+        if resolved.contains_key(&name) {
+            continue;
+        }
+        let package = package_meta(source, &name)?;
+        queue.extend(package.depends.iter().cloned());
+        resolved.insert(name, package);
+    }
+    Ok(resolved)
+}
+
+/// Resolves packages and their dependencies into a lockfile
+pub(crate) fn lock_packages(source: &dyn PackageSource, packages: &[String]) -> Result<LockFile> {
+    let target = get_target();
+    let source_name = source.source();
+
+    let mut lockfile = LockFile::default();
+    for (name, package) in resolve_packages(source, packages)? {
+        lockfile.packages.insert(
+            name,
+            LockedPackage {
+                version: package.version,
+                blake3: package.blake3,
+                source: source_name.clone(),
+                target: target.clone(),
+                depends: package.depends,
+            },
+        );
+    }
+    Ok(lockfile)
+}
+
+/// Size of the installed files of packages and their dependencies
+pub(crate) fn packages_size(source: &dyn PackageSource, packages: &[String]) -> Result<u64> {
+    let mut size = 0;
+    for (name, package) in resolve_packages(source, packages)? {
+        size += match package.storage_size {
+            0 => source.package_size(&name)?,
+            storage_size => storage_size,
+        };
+    }
+    Ok(size)
+}
+
+/// Hex blake3 hash of a file, as listed in repo metadata
+pub(crate) fn blake3_file(path: &Path) -> Result<String> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Refuses a package archive that is missing from the lockfile or has another hash
+pub(crate) fn check_locked_pkgar(name: &str, archive: &Path, lockfile: &LockFile) -> Result<()> {
+    let Some(locked) = lockfile.packages.get(name) else {
+        bail!("package {name} is not in the lockfile");
+    };
+    let blake3 = blake3_file(archive)?;
+    if blake3 != locked.blake3 {
+        bail!(
+            "package {name}: {} has hash {blake3}, differing from locked hash {} ({})",
+            archive.display(),
+            locked.blake3,
+            locked.version
+        );
+    }
+    Ok(())
+}
+
+/// Sum of the entry sizes of a pkgar file, read from its verified header
+fn pkgar_size(archive: &Path, pkey_path: &Path) -> Result<u64> {
+    let pkey = pkgar_keys::PublicKeyFile::open(pkey_path)
+        .map_err(|err| anyhow!("failed to read key {}: {err}", pkey_path.display()))?
+        .pkey;
+    let mut package = pkgar::PackageFile::new(archive, &pkey)
+        .map_err(|err| anyhow!("failed to open {}: {err}", archive.display()))?;
+    let entries = package
+        .read_entries()
+        .map_err(|err| anyhow!("failed to read entries of {}: {err}", archive.display()))?;
+    Ok(entries.iter().map(|entry| entry.size()).sum())
+}
+
+pub(crate) fn extract_pkgar(archive: &Path, pkey_path: &Path, dest: &Path) -> Result<()> {
+    println!("Install package {}", archive.display());
+    pkgar::extract(pkey_path, archive, dest).map_err(|err| {
+        anyhow!(
+            "failed to extract {} with key {}: {err}",
+            archive.display(),
+            pkey_path.display()
+        )
+    })
+}
+
+/// Packages built by a cookbook, or any local repo with the same layout
+pub struct CookbookSource {
+    repo: PathBuf,
+    pubkey: PathBuf,
+}
+
+impl CookbookSource {
+    pub fn new(cookbook: impl AsRef<Path>) -> Self {
+        let cookbook = cookbook.as_ref();
+        Self::from_repo(cookbook.join("repo"), cookbook.join("build"))
+    }
+
+    /// Local repo with the directory containing its id_ed25519.pub.toml
+    pub fn from_repo(repo: impl Into<PathBuf>, pubkey: impl Into<PathBuf>) -> Self {
+        Self {
+            repo: repo.into(),
+            pubkey: pubkey.into(),
+        }
+    }
+}
+
+impl PackageSource for CookbookSource {
+    fn source(&self) -> String {
+        format!("file://{}", self.repo.display())
+    }
+
+    fn package_toml(&self, name: &str) -> Result<String> {
+        let path = self.repo.join(get_target()).join(format!("{name}.toml"));
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))
+    }
+
+    fn install(&self, packages: &[String], dest: &Path) -> Result<()> {
+        let callback = pkg::callback::PlainCallback::new();
+        let mut library = Library::new_local(
+            self.repo.clone(),
+            self.pubkey.clone(),
+            dest.to_path_buf(),
+            &get_target(),
+            Rc::new(RefCell::new(callback)),
+        )?;
+        let packages: Vec<&String> = packages.iter().collect();
+        let packages = pkg::PackageName::from_list(packages)?;
+        library.install(packages)?;
+        library.apply()?;
+        Ok(())
+    }
+
+    fn install_locked(&self, packages: &[String], lockfile: &LockFile, dest: &Path) -> Result<()> {
+        let repo_dir = self.repo.join(get_target());
+        for name in resolve_packages(self, packages)?.keys() {
+            check_locked_pkgar(name, &repo_dir.join(format!("{name}.pkgar")), lockfile)?;
+        }
+        self.install(packages, dest)
+    }
+
+    fn install_pkgar(&self, archive: &Path, dest: &Path) -> Result<()> {
+        extract_pkgar(archive, &self.pubkey.join("id_ed25519.pub.toml"), dest)
+    }
+
+    fn package_size(&self, name: &str) -> Result<u64> {
+        let archive = self.repo.join(get_target()).join(format!("{name}.pkgar"));
+        self.pkgar_size(&archive)
+    }
+
+    fn pkgar_size(&self, archive: &Path) -> Result<u64> {
+        pkgar_size(archive, &self.pubkey.join("id_ed25519.pub.toml"))
+    }
+}
+
+/// Packages downloaded from remote repos, by priority
+pub struct RemoteSource {
+    urls: Vec<String>,
+    /// Trusted id_ed25519.pub.toml of repos, by URL
+    public_keys: BTreeMap<String, PathBuf>,
+    cache: Option<PackageCache>,
+}
+
+impl RemoteSource {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            public_keys: BTreeMap::new(),
+            cache: None,
+        }
+    }
+
+    /// Verify the packages of a repo with a trusted key instead of the key it serves
+    pub fn with_public_key(
+        mut self,
+        url: impl Into<String>,
+        public_key: impl Into<PathBuf>,
+    ) -> Self {
+        self.public_keys.insert(url.into(), public_key.into());
+        self
+    }
+
+    /// Keep downloaded packages in a cache, and install from it when possible
+    pub fn with_cache(mut self, cache: PackageCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+}
+
+impl PackageSource for RemoteSource {
+    fn source(&self) -> String {
+        self.urls.join(" ")
+    }
+
+    fn package_toml(&self, name: &str) -> Result<String> {
+        if let Some(cache) = &self.cache {
+            return cache.package_toml(&self.urls, name);
+        }
+
+        let callback = Rc::new(RefCell::new(pkg::callback::SilentCallback::new()));
+        let download_backend = pkg::net_backend::DefaultNetBackend::new()?;
+        let mut repo = pkg::RepoManager::new(callback, Box::new(download_backend));
+        for url in &self.urls {
+            repo.add_remote(url, &get_target())?;
+        }
+        let (package_toml, _) = repo.get_package_toml(&pkg::PackageName::new(name)?)?;
+        Ok(package_toml)
+    }
+
+    fn install(&self, packages: &[String], dest: &Path) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            return cache.install(&self.urls, &self.public_keys, packages, dest);
+        }
+        // pkg always verifies with the key served by the repo
+        if !self.public_keys.is_empty() {
+            bail!("repositories with a public_key require a package cache, set general.cache_dir");
+        }
+
+        let callback = pkg::callback::IndicatifCallback::new();
+        let urls: Vec<&str> = self.urls.iter().map(|url| url.as_str()).collect();
+        let mut library =
+            Library::new_remote(&urls, dest, &get_target(), Rc::new(RefCell::new(callback)))?;
+        let packages: Vec<&String> = packages.iter().collect();
+        let packages = pkg::PackageName::from_list(packages)?;
+        library.install(packages)?;
+        library.apply()?;
+        Ok(())
+    }
+
+    fn install_locked(&self, packages: &[String], lockfile: &LockFile, dest: &Path) -> Result<()> {
+        // Without a cache, pkg extracts archives as they are downloaded
+        let Some(cache) = &self.cache else {
+            bail!(
+                "locked installs from remote repos require a package cache, set general.cache_dir"
+            );
+        };
+        // The repos may no longer list the locked versions, so their metadata is not used
+        let locked = lockfile.closure(packages)?;
+        cache.install_locked(&self.urls, &self.public_keys, &locked, dest)
+    }
+}
+
+/// Directory of pkgar files and their id_ed25519.pub.toml, with optional TOML metadata
+pub struct PackageDirSource {
+    dir: PathBuf,
+}
+
+impl PackageDirSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn install_checked(
+        &self,
+        packages: &[String],
+        lockfile: Option<&LockFile>,
+        dest: &Path,
+    ) -> Result<()> {
+        // Without a repo, dependencies are only known from optional TOML metadata
+        let mut installed = BTreeSet::new();
+        let mut queue = packages.to_vec();
+        while let Some(name) = queue.pop() {
+            if !installed.insert(name.clone()) {
+                continue;
+            }
+            if self.dir.join(format!("{name}.toml")).is_file() {
+                queue.extend(package_meta(self, &name)?.depends);
+            }
+            let archive = self.dir.join(format!("{name}.pkgar"));
+            if let Some(lockfile) = lockfile {
+                check_locked_pkgar(&name, &archive, lockfile)?;
+            }
+            self.install_pkgar(&archive, dest)?;
+        }
+        Ok(())
+    }
+}
+
+impl PackageSource for PackageDirSource {
+    fn source(&self) -> String {
+        format!("file://{}", self.dir.display())
+    }
+
+    fn package_toml(&self, name: &str) -> Result<String> {
+        let path = self.dir.join(format!("{name}.toml"));
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))
+    }
+
+    fn install(&self, packages: &[String], dest: &Path) -> Result<()> {
+        self.install_checked(packages, None, dest)
+    }
+
+    fn install_locked(&self, packages: &[String], lockfile: &LockFile, dest: &Path) -> Result<()> {
+        self.install_checked(packages, Some(lockfile), dest)
+    }
+
+    fn install_pkgar(&self, archive: &Path, dest: &Path) -> Result<()> {
+        extract_pkgar(archive, &self.dir.join("id_ed25519.pub.toml"), dest)
+    }
+
+    fn package_size(&self, name: &str) -> Result<u64> {
+        self.pkgar_size(&self.dir.join(format!("{name}.pkgar")))
+    }
+
+    fn pkgar_size(&self, archive: &Path) -> Result<u64> {
+        pkgar_size(archive, &self.dir.join("id_ed25519.pub.toml"))
+    }
+}
+
+/// Returns the package source selected by the config: the cookbook, then
+/// the package directory, then the configured repositories
+pub fn package_source(config: &Config) -> Result<Box<dyn PackageSource>> {
+    if let Some(cookbook) = &config.general.cookbook {
+        return Ok(Box::new(CookbookSource::new(cookbook)));
+    }
+
+    if let Some(package_dir) = &config.general.package_dir {
+        return Ok(Box::new(PackageDirSource::new(package_dir)));
+    }
+
+    let repositories = config.package_repositories();
+    if let Some(local) = repositories.iter().find(|r| r.local_path().is_some()) {
+        if repositories.len() > 1 {
+            bail!(
+                "repository {}: file:// repositories cannot be mixed with other repositories",
+                local.name
+            );
+        }
+        let Some(public_key) = &local.public_key else {
+            bail!("repository {}: public_key is required", local.name);
+        };
+        // Library expects the directory containing id_ed25519.pub.toml
+        let public_key = Path::new(public_key);
+        if public_key.file_name() != Some(OsStr::new("id_ed25519.pub.toml")) {
+            bail!(
+                "repository {}: public_key {} of a file:// repository must be named id_ed25519.pub.toml",
+                local.name,
+                public_key.display()
+            );
+        }
+        let pubkey = public_key.parent().unwrap_or(Path::new("."));
+        return Ok(Box::new(CookbookSource::from_repo(
+            local.local_path().unwrap(),
+            pubkey,
+        )));
+    }
+
+    let mut source = RemoteSource::new(repositories.iter().map(|r| r.url.clone()).collect());
+    for repository in repositories {
+        if let Some(public_key) = repository.public_key {
+            source = source.with_public_key(repository.url, public_key);
+        }
+    }
+    let cache_dir = match &config.general.cache_dir {
+        Some(cache_dir) => Some(PathBuf::from(cache_dir)),
+        None => PackageCache::default_dir(),
+    };
+    let cache_only = config.general.cache_only.unwrap_or(false);
+    match cache_dir {
+        Some(cache_dir) => source = source.with_cache(PackageCache::new(cache_dir, cache_only)),
+        None if cache_only => bail!("no package cache directory, set general.cache_dir"),
+        None => (),
+    }
+    Ok(Box::new(source))
+}
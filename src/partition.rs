@@ -0,0 +1,398 @@
+use anyhow::{bail, Context, Result};
+use serde::Serializer;
+
+use crate::config::file::format_bytes;
+use crate::config::general::MIN_FILESYSTEM_SIZE;
+use crate::config::partition::{PartitionConfig, PartitionFilesystem, PartitionSize};
+
+const MIBI: u64 = 1024 * 1024;
+
+/// A GPT partition, in logical blocks
+#[derive(Clone, Debug, Serialize)]
+pub struct PartitionPlan {
+    pub name: String,
+    #[serde(rename = "type_guid", serialize_with = "serialize_part_type")]
+    pub part_type: gpt::partition_types::Type,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub filesystem: PartitionFilesystem,
+    pub contents: Option<String>,
+}
+
+impl PartitionPlan {
+    /// Byte offsets of the partition, end excluded
+    pub fn byte_range(&self, block_size: u64) -> (u64, u64) {
+        (
+            self.first_lba * block_size,
+            (self.last_lba + 1) * block_size,
+        )
+    }
+
+    /// Whether the UEFI bootloader is written to this partition
+    pub fn is_efi(&self) -> bool {
+        self.filesystem == PartitionFilesystem::Fat32
+            && self.part_type.guid == gpt::partition_types::EFI.guid
+    }
+}
+
+fn serialize_part_type<S: Serializer>(
+    part_type: &gpt::partition_types::Type,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&part_type.guid)
+}
+
+/// GPT partition type of RedoxFS partitions, not known by the gpt crate
+pub const REDOXFS_PART_TYPE: gpt::partition_types::Type = gpt::partition_types::Type {
+    guid: uuid::Uuid::from_u128(0xA0DB710A_D5F1_4A7D_A799_08E2EBA72AED),
+    os: gpt::partition_types::OperatingSystem::None,
+};
+
+/// Whether a GPT partition type is RedoxFS, including the LINUX_FS type
+/// written by older installers
+pub fn is_redoxfs_part_type(part_type: &gpt::partition_types::Type) -> bool {
+    part_type.guid == REDOXFS_PART_TYPE.guid
+        || part_type.guid == gpt::partition_types::LINUX_FS.guid
+}
+
+fn gpt_type(part_type: &str) -> Result<gpt::partition_types::Type> {
+    Ok(match part_type {
+        "bios" => gpt::partition_types::BIOS,
+        "efi" => gpt::partition_types::EFI,
+        "redoxfs" => REDOXFS_PART_TYPE,
+        "linux" => gpt::partition_types::LINUX_FS,
+        guid => gpt::partition_types::Type {
+            guid: uuid::Uuid::parse_str(guid)
+                .with_context(|| format!("invalid partition type '{guid}'"))?,
+            os: gpt::partition_types::OperatingSystem::None,
+        },
+    })
+}
+
+/// Computes the LBAs of configured partitions on a disk. Partitions are laid out
+/// in order on MiB boundaries, the first one starting after the GPT header, and
+/// the end of the disk is kept for the GPT table mirror.
+pub fn partition_layout(
+    disk_size: u64,
+    block_size: u64,
+    partitions: &[PartitionConfig],
+) -> Result<Vec<PartitionPlan>> {
+    // GPT reserves the protective MBR, the header and 128 entries of 128 bytes,
+    // which is 34 512-byte sectors or 6 4096-byte sectors
+    let gpt_reserved = 2 * block_size + 128 * 128;
+    let usable = (disk_size.saturating_sub(gpt_reserved) / MIBI) * MIBI;
+
+    let fixed: u64 = partitions
+        .iter()
+        .map(|partition| match partition.size {
+            PartitionSize::Mib(size) => size as u64 * MIBI,
+            PartitionSize::Rest => 0,
+        })
+        .sum();
+    if fixed > usable {
+        bail!(
+            "disk of {} is too small for {} of partitions",
+            format_bytes(disk_size),
+            format_bytes(fixed)
+        );
+    }
+    let rest = usable - fixed;
+
+    let mut layout = Vec::with_capacity(partitions.len());
+    let mut offset = 0;
+    for partition in partitions {
+        let size = match partition.size {
+            PartitionSize::Mib(size) => size as u64 * MIBI,
+            PartitionSize::Rest if rest == 0 => {
+                bail!("partition {}: no space left on the disk", partition.name);
+            }
+            PartitionSize::Rest => rest,
+        };
+        layout.push(PartitionPlan {
+            name: partition.name.clone(),
+            part_type: gpt_type(&partition.part_type)?,
+            // First MiB of the disk includes GPT tables
+            first_lba: offset.max(gpt_reserved) / block_size,
+            last_lba: (offset + size) / block_size - 1,
+            filesystem: partition.filesystem,
+            contents: partition.contents.clone(),
+        });
+        offset += size;
+    }
+    Ok(layout)
+}
+
+pub(crate) fn gpt_block_size(block_size: u64) -> Result<gpt::disk::LogicalBlockSize> {
+    Ok(match block_size {
+        512 => gpt::disk::LogicalBlockSize::Lb512,
+        4096 => gpt::disk::LogicalBlockSize::Lb4096,
+        _ => {
+            bail!("block size {block_size} not supported, GPT requires 512 or 4096");
+        }
+    })
+}
+
+/// Picks the first and last LBA of a RedoxFS partition added to free GPT space, given
+/// as (first LBA, length) ranges: the requested range, or the largest free range
+/// aligned to MiB boundaries
+pub fn free_space_range(
+    free: &[(u64, u64)],
+    range: Option<[u64; 2]>,
+    block_size: u64,
+) -> Result<(u64, u64)> {
+    let (first, last) = match range {
+        Some([first, last]) => {
+            if first > last
+                || !free
+                    .iter()
+                    .any(|&(start, len)| start <= first && last < start + len)
+            {
+                bail!("LBAs {first} to {last} are not in free space of the disk");
+            }
+            (first, last)
+        }
+        None => {
+            let align = MIBI / block_size;
+            let Some((first, end)) = free
+                .iter()
+                .map(|&(start, len)| (start.next_multiple_of(align), (start + len) / align * align))
+                .filter(|(first, end)| end > first)
+                .max_by_key(|(first, end)| end - first)
+            else {
+                bail!("no free space left on the disk");
+            };
+            (first, end - 1)
+        }
+    };
+
+    let size = (last - first + 1) * block_size;
+    if size < MIN_FILESYSTEM_SIZE as u64 * MIBI {
+        bail!(
+            "free space of {} is less than {MIN_FILESYSTEM_SIZE} MB",
+            format_bytes(size)
+        );
+    }
+    Ok((first, last))
+}
+
+/// MBR has no RedoxFS partition type, so the Linux type is used as GPT disks did before
+pub const REDOXFS_MBR_TYPE: u8 = 0x83;
+
+/// MBR partition type of a partition, None for the BIOS boot area which has no MBR entry
+pub fn mbr_type(partition: &PartitionPlan) -> Result<Option<u8>> {
+    let guid = partition.part_type.guid;
+    if guid == gpt::partition_types::BIOS.guid {
+        Ok(None)
+    } else if guid == gpt::partition_types::EFI.guid {
+        Ok(Some(0xEF))
+    } else if is_redoxfs_part_type(&partition.part_type) {
+        Ok(Some(REDOXFS_MBR_TYPE))
+    } else {
+        bail!(
+            "partition {}: type {guid} has no MBR equivalent",
+            partition.name
+        );
+    }
+}
+
+/// Builds the four 16-byte entries of an MBR partition table, marking the RedoxFS
+/// partition active. CHS addresses are set to the LBA-only marker.
+pub fn mbr_partition_table(layout: &[PartitionPlan]) -> Result<[u8; 64]> {
+    let mut table = [0; 64];
+    let mut entries = table.chunks_exact_mut(16);
+    for partition in layout {
+        let Some(mbr_type) = mbr_type(partition)? else {
+            continue;
+        };
+        let Some(entry) = entries.next() else {
+            bail!("MBR holds at most 4 partitions");
+        };
+        if partition.last_lba > u32::MAX as u64 {
+            bail!(
+                "partition {}: MBR cannot address blocks past 2^32",
+                partition.name
+            );
+        }
+        let first_lba = partition.first_lba as u32;
+        let blocks = (partition.last_lba - partition.first_lba + 1) as u32;
+        entry[0] = if partition.filesystem == PartitionFilesystem::Redoxfs {
+            0x80
+        } else {
+            0x00
+        };
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = mbr_type;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&first_lba.to_le_bytes());
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    }
+    Ok(table)
+}
+
+/// The partition RedoxFS is installed to
+pub fn redoxfs_partition(layout: &[PartitionPlan]) -> Result<&PartitionPlan> {
+    let mut partitions = layout
+        .iter()
+        .filter(|partition| partition.filesystem == PartitionFilesystem::Redoxfs);
+    match (partitions.next(), partitions.next()) {
+        (Some(partition), None) => Ok(partition),
+        _ => bail!("exactly one redoxfs partition is required"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK_SIZE: u64 = 64 * MIBI;
+
+    fn partition(name: &str, part_type: &str, size: PartitionSize) -> PartitionConfig {
+        PartitionConfig {
+            name: name.to_string(),
+            part_type: part_type.to_string(),
+            size,
+            filesystem: match part_type {
+                "efi" => PartitionFilesystem::Fat32,
+                "redoxfs" => PartitionFilesystem::Redoxfs,
+                _ => PartitionFilesystem::None,
+            },
+            contents: None,
+        }
+    }
+
+    /// Checks the layout of a disk with the default partitions, 4 MiB for EFI
+    fn check_default_layout(block_size: u64, first_lba: u64) {
+        let layout = partition_layout(
+            DISK_SIZE,
+            block_size,
+            &PartitionConfig::default_layout(Some(4)),
+        )
+        .unwrap();
+        let mib = MIBI / block_size;
+        let names: Vec<&str> = layout.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["BIOS", "EFI", "REDOX"]);
+
+        // The BIOS partition starts after the GPT header and entries
+        assert_eq!(layout[0].first_lba, first_lba);
+        assert_eq!(layout[0].last_lba, mib - 1);
+        assert_eq!(
+            (layout[1].first_lba, layout[1].last_lba),
+            (mib, 5 * mib - 1)
+        );
+        assert_eq!(layout[2].first_lba, 5 * mib);
+        // The rest ends on the last whole MiB before the GPT mirror
+        assert_eq!(layout[2].last_lba, 63 * mib - 1);
+
+        for pair in layout.windows(2) {
+            assert_eq!(pair[0].last_lba + 1, pair[1].first_lba);
+            assert_eq!(pair[1].first_lba % mib, 0);
+        }
+        assert!(layout[1].is_efi());
+        assert_eq!(redoxfs_partition(&layout).unwrap().name, "REDOX");
+    }
+
+    #[test]
+    fn layout_512() {
+        // Protective MBR, header and 32 sectors of entries
+        check_default_layout(512, 34);
+    }
+
+    #[test]
+    fn layout_4096() {
+        // Protective MBR, header and 4 sectors of entries
+        check_default_layout(4096, 6);
+    }
+
+    #[test]
+    fn layout_gpt_mirror() {
+        // Without a whole MiB left after the GPT mirror, the rest ends one MiB earlier
+        let disk_size = 64 * MIBI + 2 * 512 + 128 * 128 - 1;
+        let layout =
+            partition_layout(disk_size, 512, &PartitionConfig::default_layout(None)).unwrap();
+        assert_eq!(layout[2].last_lba, 63 * 2048 - 1);
+
+        let disk_size = 64 * MIBI + 2 * 512 + 128 * 128;
+        let layout =
+            partition_layout(disk_size, 512, &PartitionConfig::default_layout(None)).unwrap();
+        assert_eq!(layout[2].last_lba, 64 * 2048 - 1);
+        // The mirror of the entries and header fits after the last partition
+        assert!((layout[2].last_lba + 1) * 512 + 128 * 128 + 512 <= disk_size);
+    }
+
+    #[test]
+    fn layout_errors() {
+        let partitions = [
+            partition("DATA", "linux", PartitionSize::Mib(64)),
+            partition("REDOX", "redoxfs", PartitionSize::Rest),
+        ];
+        assert!(partition_layout(DISK_SIZE, 512, &partitions).is_err());
+
+        let partitions = [
+            partition("DATA", "linux", PartitionSize::Mib(63)),
+            partition("REDOX", "redoxfs", PartitionSize::Rest),
+        ];
+        assert!(partition_layout(DISK_SIZE, 512, &partitions).is_err());
+
+        let partitions = [partition("DATA", "not-a-guid", PartitionSize::Mib(1))];
+        assert!(partition_layout(DISK_SIZE, 512, &partitions).is_err());
+    }
+
+    #[test]
+    fn mbr_table() {
+        let layout =
+            partition_layout(DISK_SIZE, 512, &PartitionConfig::default_layout(Some(4))).unwrap();
+        let table = mbr_partition_table(&layout).unwrap();
+
+        // The BIOS area has no entry, so EFI comes first
+        let efi = &table[0..16];
+        assert_eq!(efi[0], 0x00);
+        assert_eq!(efi[1..4], [0xFE, 0xFF, 0xFF]);
+        assert_eq!(efi[4], 0xEF);
+        assert_eq!(efi[5..8], [0xFE, 0xFF, 0xFF]);
+        assert_eq!(efi[8..12], 2048u32.to_le_bytes());
+        assert_eq!(efi[12..16], (4 * 2048u32).to_le_bytes());
+
+        // RedoxFS is active, with the Linux type
+        let redoxfs = &table[16..32];
+        assert_eq!(redoxfs[0], 0x80);
+        assert_eq!(redoxfs[4], REDOXFS_MBR_TYPE);
+        assert_eq!(redoxfs[8..12], (5 * 2048u32).to_le_bytes());
+        assert_eq!(redoxfs[12..16], (58 * 2048u32).to_le_bytes());
+
+        assert_eq!(table[32..], [0; 32]);
+    }
+
+    #[test]
+    fn mbr_table_errors() {
+        let mut partitions = vec![partition("BIOS", "bios", PartitionSize::Mib(1))];
+        for i in 0..4 {
+            partitions.push(partition(&format!("EFI{i}"), "efi", PartitionSize::Mib(1)));
+        }
+        partitions.push(partition("REDOX", "redoxfs", PartitionSize::Rest));
+        let layout = partition_layout(DISK_SIZE, 512, &partitions).unwrap();
+        assert!(mbr_partition_table(&layout).is_err());
+        // Four entries besides the BIOS area fit
+        assert!(mbr_partition_table(&layout[1..]).is_err());
+        assert!(mbr_partition_table(&layout[2..]).is_ok());
+
+        // Microsoft basic data, with no MBR type here
+        let partitions = [partition(
+            "DATA",
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+            PartitionSize::Mib(1),
+        )];
+        let layout = partition_layout(DISK_SIZE, 512, &partitions).unwrap();
+        assert!(mbr_partition_table(&layout).is_err());
+
+        // Past 2 TiB with 512-byte blocks
+        let layout = [PartitionPlan {
+            name: "REDOX".to_string(),
+            part_type: REDOXFS_PART_TYPE,
+            first_lba: 2048,
+            last_lba: u32::MAX as u64 + 1,
+            filesystem: PartitionFilesystem::Redoxfs,
+            contents: None,
+        }];
+        assert!(mbr_partition_table(&layout).is_err());
+    }
+}
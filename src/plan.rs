@@ -0,0 +1,553 @@
+use anyhow::{bail, Context, Result};
+
+use crate::config::file::{format_bytes, FileConfig};
+use crate::config::general::{FilesystemSize, ImageFormat, MIN_FILESYSTEM_SIZE};
+use crate::config::package::PackageConfig;
+use crate::config::partition::{PartitionFilesystem, PartitionScheme};
+use crate::config::Config;
+use crate::disk_wrapper::{disk_geometry, DiskWrapper, DEFAULT_BLOCK_SIZE};
+use crate::image;
+use crate::installer::{efi_bootloader_name, existing_redoxfs_range, filesystem_size, get_target};
+use crate::package_source::package_source;
+use crate::partition::{
+    free_space_range, gpt_block_size, mbr_partition_table, mbr_type, partition_layout,
+    redoxfs_partition, PartitionPlan, REDOXFS_PART_TYPE,
+};
+use crate::target::{check_redoxfs_partition, find_gpt_partition, PartitionSelector};
+
+use std::{fmt, fs::File, path::Path};
+
+const MIBI: u64 = 1024 * 1024;
+
+/// Existing partitions of a GPT disk, and the RedoxFS partition added to its free space
+fn alongside_layout(
+    output: &Path,
+    image_block_size: Option<u32>,
+    range: Option<[u64; 2]>,
+) -> Result<Vec<PartitionPlan>> {
+    // Opened like the installer does, so that image formats other than raw are read as disks
+    let mut disk_file =
+        DiskWrapper::open_read_only(output, image_block_size.map(|size| size as usize))?;
+    let block_size = disk_file.block_size() as u64;
+    let gpt_disk = gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(gpt_block_size(block_size)?)
+        .open_from_device(Box::new(&mut disk_file))
+        .context("failed to read GPT, alongside requires a GPT disk")?;
+
+    let mut layout: Vec<PartitionPlan> = gpt_disk
+        .partitions()
+        .values()
+        .map(|partition| PartitionPlan {
+            name: partition.name.clone(),
+            part_type: partition.part_type_guid.clone(),
+            first_lba: partition.first_lba,
+            last_lba: partition.last_lba,
+            filesystem: if partition.part_type_guid.guid == gpt::partition_types::EFI.guid {
+                PartitionFilesystem::Fat32
+            } else {
+                PartitionFilesystem::None
+            },
+            contents: None,
+        })
+        .collect();
+
+    let (first_lba, last_lba) = free_space_range(&gpt_disk.find_free_sectors(), range, block_size)?;
+    layout.push(PartitionPlan {
+        name: "REDOX".to_string(),
+        part_type: REDOXFS_PART_TYPE,
+        first_lba,
+        last_lba,
+        filesystem: PartitionFilesystem::Redoxfs,
+        contents: None,
+    });
+    Ok(layout)
+}
+
+/// What [crate::install] would do with a config, computed without side effects
+#[derive(Debug, Serialize)]
+pub struct InstallPlan {
+    pub output: String,
+    pub target: String,
+    /// Absent when installing to a directory
+    pub disk: Option<DiskPlan>,
+    pub packages: Vec<String>,
+    pub files: Vec<FilePlan>,
+    pub users: Vec<UserPlan>,
+    pub groups: Vec<GroupPlan>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskPlan {
+    /// Whether the image file is created with the configured filesystem size
+    pub create: bool,
+    /// Whether existing partitions are kept, only adding the RedoxFS partition
+    pub alongside: bool,
+    /// Whether the existing RedoxFS is updated, keeping partitions and other files
+    pub update: bool,
+    /// Format of the image file, raw for block devices
+    pub format: ImageFormat,
+    pub partition_scheme: PartitionScheme,
+    /// Unresolved when an "auto" filesystem_size needs package metadata that is not cached
+    pub size: Option<u64>,
+    pub block_size: u64,
+    /// Empty when partitioning is skipped or the size is unresolved
+    pub partitions: Vec<PartitionPlan>,
+    pub efi_files: Vec<EfiFilePlan>,
+    pub redoxfs_size: Option<u64>,
+    pub encrypted: bool,
+    pub no_mount: bool,
+    /// Whether the RedoxFS of a new live raw image written with no_mount and the image are
+    /// cut down to their content, the sizes above then being upper bounds
+    pub shrink: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EfiFilePlan {
+    pub partition: String,
+    pub path: String,
+    /// Bootloader sizes are only known once the bootloader package is fetched
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilePlan {
+    pub path: String,
+    pub kind: &'static str,
+    pub postinstall: bool,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePlan {
+    fn new(file: &FileConfig) -> Self {
+        let kind = if file.directory {
+            "directory"
+        } else if file.symlink {
+            "symlink"
+        } else if file.append {
+            "append"
+        } else {
+            "file"
+        };
+        Self {
+            path: file.path.clone(),
+            kind,
+            postinstall: file.postinstall,
+            size: file.data.len() as u64,
+            mode: file.mode,
+            uid: file.uid,
+            gid: file.gid,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPlan {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+    pub shell: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupPlan {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// Returns the size and block size of an existing disk, without opening it for writing
+fn disk_size(output: &Path, image_block_size: Option<u32>) -> Result<(u64, u64)> {
+    let mut file = File::open(output)?;
+    let (size, block_size) = disk_geometry(&mut file, image_block_size.map(|size| size as usize))?;
+    Ok((size, block_size as u64))
+}
+
+/// Size in MB of an "auto" filesystem_size from local package sources or the package
+/// cache, None if package metadata would have to be downloaded
+fn cached_filesystem_size(config: &Config) -> Option<u32> {
+    let mut config = config.clone();
+    config.general.cache_only = Some(true);
+    let source = package_source(&config).ok()?;
+    filesystem_size(&config, source.as_ref()).ok()
+}
+
+/// Computes the install plan of a config without creating the image, downloading,
+/// or mounting anything. Disks are only opened for reading
+pub fn install_plan(config: &Config, output: impl AsRef<Path>) -> Result<InstallPlan> {
+    let output = output.as_ref();
+    let target = get_target();
+
+    let selector = output.to_str().map(PartitionSelector::from_target);
+    let disk = if let Some(Some(selector)) = selector.transpose()? {
+        let (disk_path, partition) = find_gpt_partition(&selector, &[], config.general.block_size)?;
+        check_redoxfs_partition(&partition, config.general.force.unwrap_or(false))?;
+        let (size, block_size) = disk_size(&disk_path, config.general.block_size)?;
+        let partition = PartitionPlan {
+            name: partition.name,
+            part_type: partition.part_type_guid,
+            first_lba: partition.first_lba,
+            last_lba: partition.last_lba,
+            filesystem: PartitionFilesystem::Redoxfs,
+            contents: None,
+        };
+        let (start, end) = partition.byte_range(block_size);
+        Some(DiskPlan {
+            create: false,
+            alongside: false,
+            update: false,
+            format: ImageFormat::Raw,
+            partition_scheme: PartitionScheme::Gpt,
+            size: Some(size),
+            block_size,
+            partitions: vec![partition],
+            efi_files: Vec::new(),
+            redoxfs_size: Some(end - start),
+            encrypted: config.general.encrypt_disk.is_some(),
+            no_mount: config.general.no_mount.unwrap_or(false),
+            shrink: false,
+        })
+    } else if output.is_dir() {
+        None
+    } else {
+        let requested_format = config
+            .general
+            .image_format
+            .unwrap_or_else(|| ImageFormat::from_path(output));
+        // ISO images are always written anew
+        let create = !output.exists() || requested_format == ImageFormat::Iso;
+        let update = config.general.update.unwrap_or(false);
+        if update && create {
+            bail!("{} must be an existing disk to update", output.display());
+        }
+        let (size, block_size) = if create {
+            let fs_size = match config.general.filesystem_size {
+                Some(FilesystemSize::Auto) => cached_filesystem_size(config),
+                Some(FilesystemSize::Mb(size)) => Some(size),
+                None => Some(0),
+            };
+            if fs_size.is_some_and(|size| size < MIN_FILESYSTEM_SIZE) {
+                bail!("Refusing to create image disk less than {MIN_FILESYSTEM_SIZE} MB");
+            }
+            let block_size = config
+                .general
+                .block_size
+                .map_or(DEFAULT_BLOCK_SIZE as u64, |size| size as u64);
+            (fs_size.map(|size| size as u64 * MIBI), block_size)
+        } else {
+            let (size, block_size) = disk_size(output, config.general.block_size)?;
+            (Some(size), block_size)
+        };
+
+        let format = if create {
+            requested_format
+        } else if output.is_file() {
+            image::detect(&File::open(output)?)?
+        } else {
+            ImageFormat::Raw
+        };
+        let alongside = config.general.alongside.unwrap_or(false);
+        let no_mount = config.general.no_mount.unwrap_or(false);
+        // Same condition as the install, ISO images being written from a live raw image
+        let shrink = create
+            && no_mount
+            && match format {
+                ImageFormat::Raw => config.general.live_disk.unwrap_or(false),
+                ImageFormat::Iso => true,
+                _ => false,
+            };
+        let partition_scheme = config.general.partition_scheme.unwrap_or_default();
+        let (partitions, efi_files, redoxfs_size) =
+            if config.general.skip_partitions.unwrap_or(false) {
+                (
+                    Vec::new(),
+                    Vec::new(),
+                    size.map(|size| size.next_multiple_of(block_size)),
+                )
+            } else if update {
+                let mut disk_file = DiskWrapper::open_read_only(
+                    output,
+                    config.general.block_size.map(|size| size as usize),
+                )?;
+                let (start, end) = existing_redoxfs_range(&mut disk_file)?;
+                (Vec::new(), Vec::new(), Some(end - start))
+            } else if alongside {
+                if create {
+                    bail!("{} must be an existing GPT disk", output.display());
+                }
+                let partitions = alongside_layout(
+                    output,
+                    config.general.block_size,
+                    config.general.alongside_range,
+                )?;
+                let redoxfs = redoxfs_partition(&partitions)?;
+                let (start, end) = redoxfs.byte_range(block_size);
+                let Some(esp) = partitions.iter().find(|partition| partition.is_efi()) else {
+                    bail!("{} has no EFI system partition", output.display());
+                };
+                let efi_files = vec![EfiFilePlan {
+                    partition: esp.name.clone(),
+                    path: format!("EFI/redox/{}", efi_bootloader_name(&target)?),
+                    size: None,
+                }];
+                (partitions, efi_files, Some(end - start))
+            } else if let Some(size) = size {
+                let partitions = partition_layout(size, block_size, &config.partition_layout())?;
+                if partition_scheme == PartitionScheme::Mbr {
+                    mbr_partition_table(&partitions)?;
+                }
+                let redoxfs = redoxfs_partition(&partitions)?;
+                let (start, end) = redoxfs.byte_range(block_size);
+                let mut efi_files = Vec::new();
+                for partition in partitions.iter().filter(|partition| partition.is_efi()) {
+                    efi_files.push(EfiFilePlan {
+                        partition: partition.name.clone(),
+                        path: format!("EFI/BOOT/{}", efi_bootloader_name(&target)?),
+                        size: None,
+                    });
+                }
+                (partitions, efi_files, Some(end - start))
+            } else {
+                // The layout depends on the unresolved size
+                (Vec::new(), Vec::new(), None)
+            };
+
+        Some(DiskPlan {
+            create,
+            alongside,
+            update,
+            format,
+            partition_scheme,
+            size,
+            block_size,
+            partitions,
+            efi_files,
+            redoxfs_size,
+            encrypted: config.general.encrypt_disk.is_some(),
+            no_mount,
+            shrink,
+        })
+    };
+
+    let packages = config
+        .packages
+        .iter()
+        .filter_map(|(name, package)| match package {
+            PackageConfig::Build(rule) if rule == "ignore" => None,
+            PackageConfig::Spec {
+                path: Some(path), ..
+            } => Some(format!("{name} ({path})")),
+            PackageConfig::Spec {
+                version: Some(version),
+                ..
+            } => Some(format!("{name} ={version}")),
+            _ => Some(name.clone()),
+        })
+        .collect();
+
+    // Same order as install_dir
+    let mut files: Vec<FilePlan> = config
+        .files
+        .iter()
+        .filter(|file| !file.postinstall)
+        .chain(config.files.iter().filter(|file| file.postinstall))
+        .map(FilePlan::new)
+        .collect();
+    for repository in &config.repositories {
+        for mut file in repository.pkg_d_files()? {
+            file.postinstall = true;
+            files.push(FilePlan::new(&file));
+        }
+    }
+
+    let ids = config.assigned_ids();
+    let users = config
+        .users
+        .iter()
+        .map(|(username, user)| {
+            let (uid, gid) = ids.users[username];
+            UserPlan {
+                name: username.clone(),
+                uid,
+                gid,
+                home: user.home.clone().unwrap_or(format!("/home/{}", username)),
+                shell: user.shell.clone().unwrap_or("/bin/ion".into()),
+            }
+        })
+        .collect();
+    let groups = config
+        .groups
+        .iter()
+        .map(|(name, group)| GroupPlan {
+            name: name.clone(),
+            gid: ids.groups[name],
+            members: group.members.clone(),
+        })
+        .collect();
+
+    Ok(InstallPlan {
+        output: output.display().to_string(),
+        target,
+        disk,
+        packages,
+        files,
+        users,
+        groups,
+    })
+}
+
+impl fmt::Display for InstallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Install plan for {} ({})", self.output, self.target)?;
+
+        match &self.disk {
+            Some(disk) => {
+                // Shrunk disks end where their content does
+                let bound = if disk.shrink { "at most " } else { "" };
+                let size = match disk.size {
+                    Some(size) => format!("{bound}{} ({size} bytes)", format_bytes(size)),
+                    None => {
+                        "auto size, unresolved without downloading package metadata".to_string()
+                    }
+                };
+                writeln!(
+                    f,
+                    "Disk: {}, block size {}, {} image{}",
+                    size,
+                    disk.block_size,
+                    disk.format,
+                    if disk.create {
+                        ", created"
+                    } else if disk.update {
+                        ", updating its RedoxFS"
+                    } else if disk.alongside {
+                        ", keeping existing partitions"
+                    } else {
+                        ""
+                    }
+                )?;
+                if disk.update {
+                    writeln!(f, "Partitions: kept")?;
+                } else if disk.size.is_none() {
+                    writeln!(f, "Partitions: unresolved")?;
+                } else if disk.partitions.is_empty() {
+                    writeln!(f, "Partitions: skipped")?;
+                } else {
+                    writeln!(f, "Partitions ({:?}):", disk.partition_scheme)?;
+                }
+                for (i, partition) in disk.partitions.iter().enumerate() {
+                    let (start, end) = partition.byte_range(disk.block_size);
+                    let part_type = match disk.partition_scheme {
+                        PartitionScheme::Gpt => partition.part_type.guid.to_string(),
+                        PartitionScheme::Mbr => match mbr_type(partition) {
+                            Ok(Some(mbr_type)) => format!("{mbr_type:#04X}"),
+                            _ => "none".to_string(),
+                        },
+                    };
+                    write!(
+                        f,
+                        "  {}: {} type {} LBA {}..={} ({}) {:?}",
+                        i + 1,
+                        partition.name,
+                        part_type,
+                        partition.first_lba,
+                        partition.last_lba,
+                        format_bytes(end - start),
+                        partition.filesystem
+                    )?;
+                    if let Some(contents) = &partition.contents {
+                        write!(f, " from {contents}")?;
+                    }
+                    writeln!(f)?;
+                }
+                for file in &disk.efi_files {
+                    match file.size {
+                        Some(size) => writeln!(
+                            f,
+                            "EFI file: {}:{} ({})",
+                            file.partition,
+                            file.path,
+                            format_bytes(size)
+                        )?,
+                        None => writeln!(
+                            f,
+                            "EFI file: {}:{} (bootloader size)",
+                            file.partition, file.path
+                        )?,
+                    }
+                }
+                let redoxfs_size = match disk.redoxfs_size {
+                    Some(size) => format!("{bound}{} ({size} bytes)", format_bytes(size)),
+                    None => "unresolved".to_string(),
+                };
+                writeln!(
+                    f,
+                    "RedoxFS: {}{}{}",
+                    redoxfs_size,
+                    if disk.encrypted { ", encrypted" } else { "" },
+                    if disk.shrink {
+                        ", written as archive, final size depends on the content"
+                    } else if disk.no_mount {
+                        ", written as archive"
+                    } else {
+                        ""
+                    }
+                )?;
+            }
+            None => writeln!(f, "Directory: no disk is written")?,
+        }
+
+        writeln!(f, "Packages ({}):", self.packages.len())?;
+        for package in &self.packages {
+            writeln!(f, "  {package}")?;
+        }
+
+        writeln!(f, "Files ({}):", self.files.len())?;
+        for file in &self.files {
+            write!(
+                f,
+                "  {} {} {} {}",
+                if file.postinstall { "post" } else { "pre " },
+                file.kind,
+                file.path,
+                format_bytes(file.size)
+            )?;
+            if let Some(mode) = file.mode {
+                write!(f, " mode={mode:o}")?;
+            }
+            if let Some(uid) = file.uid {
+                write!(f, " uid={uid}")?;
+            }
+            if let Some(gid) = file.gid {
+                write!(f, " gid={gid}")?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "Users ({}):", self.users.len())?;
+        for user in &self.users {
+            writeln!(
+                f,
+                "  {} uid={} gid={} home={} shell={}",
+                user.name, user.uid, user.gid, user.home, user.shell
+            )?;
+        }
+
+        writeln!(f, "Groups ({}):", self.groups.len())?;
+        for group in &self.groups {
+            writeln!(
+                f,
+                "  {} gid={} members={}",
+                group.name,
+                group.gid,
+                group.members.join(",")
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::{Context, Result};
+use rand::{rngs::OsRng, TryRngCore};
+use redoxfs::{Disk, FileSystem, Node, Transaction, TreePtr};
+
+use crate::installer::syscall_error;
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Namespace of the GUIDs derived from reproducible_seed
+const SEED_NAMESPACE: uuid::Uuid = uuid::Uuid::from_u128(0x5E3D_4C1A_96B2_4F0E_8A7D_2C61_B0F9_E847);
+
+/// Source of the values that otherwise differ between two installs of the same config.
+/// GUIDs and password salts are derived from the reproducible_seed of the config,
+/// timestamps come from SOURCE_DATE_EPOCH. Either one falls back to random values
+/// or the current time when it is not set.
+///
+/// RedoxFS encryption keys are always random, so encrypted disks are never reproducible.
+#[derive(Clone, Debug, Default)]
+pub struct Reproducible {
+    namespace: Option<uuid::Uuid>,
+    epoch: Option<u64>,
+}
+
+impl Reproducible {
+    /// Uses the given seed and the SOURCE_DATE_EPOCH environment variable, if set
+    pub fn new(seed: Option<&str>) -> Result<Self> {
+        let epoch = match env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => Some(
+                epoch
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid SOURCE_DATE_EPOCH '{epoch}'"))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(Self {
+            namespace: seed.map(|seed| uuid::Uuid::new_v5(&SEED_NAMESPACE, seed.as_bytes())),
+            epoch,
+        })
+    }
+
+    /// SOURCE_DATE_EPOCH, if set
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
+    /// GUID derived from the seed for a name unique within the install, if seeded
+    pub fn seeded_guid(&self, name: &str) -> Option<uuid::Uuid> {
+        self.namespace
+            .map(|namespace| uuid::Uuid::new_v5(&namespace, name.as_bytes()))
+    }
+
+    /// Same as [Reproducible::seeded_guid], with a random GUID when not seeded
+    pub fn guid(&self, name: &str) -> uuid::Uuid {
+        self.seeded_guid(name).unwrap_or_else(uuid::Uuid::new_v4)
+    }
+
+    /// Password salt of a user, in the format of [crate::hash_password]
+    pub fn salt(&self, username: &str) -> Result<String> {
+        let salt = match self.seeded_guid(&format!("salt:{username}")) {
+            Some(guid) => guid.as_u64_pair().0,
+            None => OsRng.try_next_u64()?,
+        };
+        Ok(format!("{salt:X}"))
+    }
+
+    /// Creation time of new filesystems
+    pub fn ctime(&self) -> Result<Duration> {
+        match self.epoch {
+            Some(epoch) => Ok(Duration::from_secs(epoch)),
+            None => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?),
+        }
+    }
+
+    /// Sets the access and modification times of a directory and everything below it
+    /// to SOURCE_DATE_EPOCH, if set. Change times can only be set within RedoxFS,
+    /// see [Reproducible::set_redoxfs_times].
+    pub fn set_times(&self, path: &Path) -> Result<()> {
+        match self.epoch {
+            Some(epoch) => set_times(path, epoch),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the change, modification and access times of every RedoxFS node
+    /// to SOURCE_DATE_EPOCH, if set. Nodes that already have it are not rewritten.
+    pub fn set_redoxfs_times<D: Disk>(&self, fs: &mut FileSystem<D>) -> Result<()> {
+        let Some(epoch) = self.epoch else {
+            return Ok(());
+        };
+        fs.tx(|tx| {
+            set_node_times(tx, TreePtr::root(), epoch)?;
+
+            // Squash alloc log
+            tx.sync(true)
+        })
+        .map_err(syscall_error)
+        .context("failed to set RedoxFS times")
+    }
+
+    /// FAT filesystem options, stamping new files with SOURCE_DATE_EPOCH if set
+    pub fn fat_options(&self) -> fatfs::FsOptions {
+        let options = fatfs::FsOptions::new();
+        match self.epoch {
+            Some(epoch) => options.time_provider(EpochTimeProvider::get(epoch)),
+            None => options,
+        }
+    }
+}
+
+fn set_times(path: &Path, epoch: u64) -> Result<()> {
+    // Directory times change when their entries do, so set them last
+    if path.symlink_metadata()?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_times(&entry?.path(), epoch)?;
+        }
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let time = libc::timespec {
+        tv_sec: epoch as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [time, time];
+    // Symlinks get their own times instead of those of their target
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to set times of {}", path.display()));
+    }
+    Ok(())
+}
+
+fn set_node_times<D: Disk>(
+    tx: &mut Transaction<D>,
+    ptr: TreePtr<Node>,
+    epoch: u64,
+) -> syscall::Result<()> {
+    let mut node = tx.read_tree(ptr)?;
+    if node.data().is_dir() {
+        let mut children = Vec::new();
+        tx.child_nodes(ptr, &mut children)?;
+        for child in children {
+            set_node_times(tx, child.node_ptr(), epoch)?;
+        }
+    }
+
+    let data = node.data();
+    let time = (epoch, 0);
+    if data.ctime() != time || data.mtime() != time || data.atime() != time {
+        let data = node.data_mut();
+        data.set_ctime(epoch, 0);
+        data.set_mtime(epoch, 0);
+        data.set_atime(epoch, 0);
+        tx.sync_tree(node)?;
+    }
+    Ok(())
+}
+
+/// SOURCE_DATE_EPOCH as a FAT timestamp
+#[derive(Debug)]
+struct EpochTimeProvider(fatfs::DateTime);
+
+impl EpochTimeProvider {
+    /// fatfs requires a static time provider, so one is leaked for each epoch
+    fn get(epoch: u64) -> &'static Self {
+        static PROVIDERS: Mutex<BTreeMap<u64, &'static EpochTimeProvider>> =
+            Mutex::new(BTreeMap::new());
+        PROVIDERS
+            .lock()
+            .unwrap()
+            .entry(epoch)
+            .or_insert_with(|| Box::leak(Box::new(Self(dos_date_time(epoch)))))
+    }
+}
+
+impl fatfs::TimeProvider for EpochTimeProvider {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        self.0
+    }
+}
+
+/// Converts seconds since the Unix epoch to a FAT timestamp in UTC,
+/// clamped to the years 1980 to 2107 that FAT can represent
+fn dos_date_time(epoch: u64) -> fatfs::DateTime {
+    let [year, month, day, hour, min, sec] = civil_time(epoch.clamp(315_532_800, 4_354_819_199));
+    fatfs::DateTime {
+        date: fatfs::Date {
+            year: year as u16,
+            month: month as u16,
+            day: day as u16,
+        },
+        time: fatfs::Time {
+            hour: hour as u16,
+            min: min as u16,
+            sec: sec as u16,
+            millis: 0,
+        },
+    }
+}
+
+/// Converts seconds since the Unix epoch to the year, month, day, hour, minute and second in UTC
+pub(crate) fn civil_time(epoch: u64) -> [u64; 6] {
+    let (days, secs) = (epoch / 86400, epoch % 86400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    [year, month, day, secs / 3600, secs / 60 % 60, secs % 60]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeded(seed: &str) -> Reproducible {
+        Reproducible {
+            namespace: Some(uuid::Uuid::new_v5(&SEED_NAMESPACE, seed.as_bytes())),
+            epoch: None,
+        }
+    }
+
+    fn date_time(date: [u16; 3], time: [u16; 3]) -> fatfs::DateTime {
+        fatfs::DateTime {
+            date: fatfs::Date {
+                year: date[0],
+                month: date[1],
+                day: date[2],
+            },
+            time: fatfs::Time {
+                hour: time[0],
+                min: time[1],
+                sec: time[2],
+                millis: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn civil_times() {
+        assert_eq!(civil_time(0), [1970, 1, 1, 0, 0, 0]);
+        // Leap days, including that of a year divisible by 400
+        assert_eq!(civil_time(951_782_400), [2000, 2, 29, 0, 0, 0]);
+        assert_eq!(civil_time(1_709_164_799), [2024, 2, 28, 23, 59, 59]);
+        assert_eq!(civil_time(1_700_000_000), [2023, 11, 14, 22, 13, 20]);
+        assert_eq!(civil_time(4_354_819_199), [2107, 12, 31, 23, 59, 59]);
+    }
+
+    #[test]
+    fn dos_date_times() {
+        assert_eq!(
+            dos_date_time(1_700_000_000),
+            date_time([2023, 11, 14], [22, 13, 20])
+        );
+        // Clamped to the range of FAT
+        assert_eq!(dos_date_time(0), date_time([1980, 1, 1], [0, 0, 0]));
+        assert_eq!(
+            dos_date_time(315_532_799),
+            date_time([1980, 1, 1], [0, 0, 0])
+        );
+        assert_eq!(
+            dos_date_time(4_354_819_200),
+            date_time([2107, 12, 31], [23, 59, 59])
+        );
+        assert_eq!(
+            dos_date_time(u64::MAX),
+            date_time([2107, 12, 31], [23, 59, 59])
+        );
+    }
+
+    #[test]
+    fn fat_time_provider_per_epoch() {
+        let provider = EpochTimeProvider::get(1_700_000_000);
+        assert!(std::ptr::eq(
+            provider,
+            EpochTimeProvider::get(1_700_000_000)
+        ));
+        assert!(!std::ptr::eq(
+            provider,
+            EpochTimeProvider::get(1_700_000_001)
+        ));
+    }
+
+    #[test]
+    fn seeded_guids() {
+        // Changing these changes the GUIDs of every seeded image
+        assert_eq!(
+            seeded("redox").seeded_guid("disk").unwrap().to_string(),
+            "02a1db3b-ec5f-50e5-aa27-07be68d39c6d"
+        );
+        assert_eq!(
+            seeded("redox").seeded_guid("disk"),
+            seeded("redox").seeded_guid("disk")
+        );
+        assert_ne!(
+            seeded("redox").seeded_guid("disk"),
+            seeded("redox").seeded_guid("efi")
+        );
+        assert_ne!(
+            seeded("redox").seeded_guid("disk"),
+            seeded("other").seeded_guid("disk")
+        );
+
+        let unseeded = Reproducible::default();
+        assert_eq!(unseeded.seeded_guid("disk"), None);
+        assert_ne!(unseeded.guid("disk"), unseeded.guid("disk"));
+    }
+
+    #[test]
+    fn salts() {
+        let salt = seeded("redox").salt("user").unwrap();
+        assert_eq!(salt, "DEF4ACDAA6255483");
+        assert_eq!(salt, seeded("redox").salt("user").unwrap());
+        assert_ne!(salt, seeded("redox").salt("root").unwrap());
+        assert_ne!(salt, seeded("other").salt("user").unwrap());
+
+        let unseeded = Reproducible::default();
+        assert_ne!(
+            unseeded.salt("user").unwrap(),
+            unseeded.salt("user").unwrap()
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+
+use crate::disk_wrapper::DiskWrapper;
+use crate::partition::{gpt_block_size, is_redoxfs_part_type};
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Existing GPT partition to install into, written as "gpt-guid:<uuid>" or "gpt-label:<name>"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionSelector {
+    /// Unique partition GUID, stable across device renumbering
+    Guid(uuid::Uuid),
+    /// GPT partition name, which must match a single partition
+    Label(String),
+}
+
+impl PartitionSelector {
+    /// Parses an install target, returning None if it is not a partition selector
+    pub fn from_target(target: &str) -> Result<Option<Self>> {
+        if let Some(guid) = target.strip_prefix("gpt-guid:") {
+            let guid = uuid::Uuid::parse_str(guid)
+                .with_context(|| format!("invalid partition GUID '{guid}'"))?;
+            Ok(Some(PartitionSelector::Guid(guid)))
+        } else if let Some(label) = target.strip_prefix("gpt-label:") {
+            if label.is_empty() {
+                bail!("empty partition label");
+            }
+            Ok(Some(PartitionSelector::Label(label.to_string())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn matches(&self, partition: &gpt::partition::Partition) -> bool {
+        match self {
+            PartitionSelector::Guid(guid) => &partition.part_guid == guid,
+            PartitionSelector::Label(label) => &partition.name == label,
+        }
+    }
+}
+
+impl fmt::Display for PartitionSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionSelector::Guid(guid) => write!(f, "gpt-guid:{guid}"),
+            PartitionSelector::Label(label) => write!(f, "gpt-label:{label}"),
+        }
+    }
+}
+
+/// Reads the GPT partitions of a disk or image file, using its logical block size.
+/// Image files use image_block_size if given, like general.block_size
+pub fn gpt_partitions(
+    disk_path: impl AsRef<Path>,
+    image_block_size: Option<u32>,
+) -> Result<BTreeMap<u32, gpt::partition::Partition>> {
+    let mut disk_file = DiskWrapper::open_read_only(
+        disk_path.as_ref(),
+        image_block_size.map(|size| size as usize),
+    )?;
+    let block_size = disk_file.block_size() as u64;
+    let gpt_disk = gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(gpt_block_size(block_size)?)
+        .open_from_device(Box::new(&mut disk_file))?;
+    Ok(gpt_disk.partitions().clone())
+}
+
+/// Refuses to format a partition that is not RedoxFS, such as an EFI system partition
+/// or a Linux filesystem found by mistake, unless forced
+pub fn check_redoxfs_partition(partition: &gpt::partition::Partition, force: bool) -> Result<()> {
+    if !force && !is_redoxfs_part_type(&partition.part_type_guid) {
+        bail!(
+            "partition {} ({}) has type {}, not RedoxFS, use --force to format it anyway",
+            partition.name,
+            partition.part_guid,
+            partition.part_type_guid.guid
+        );
+    }
+    Ok(())
+}
+
+/// Whole disks of the running system
+#[cfg(target_os = "linux")]
+fn system_disks() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir("/sys/block") else {
+        return Vec::new();
+    };
+    let mut disks: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| !["ram", "zram", "sr"].iter().any(|p| name.starts_with(p)))
+        .map(|name| Path::new("/dev").join(name))
+        .collect();
+    disks.sort();
+    disks
+}
+
+/// Whole disks of the running system
+#[cfg(target_os = "redox")]
+fn system_disks() -> Vec<PathBuf> {
+    let Ok(schemes) = fs::read_dir("/scheme") else {
+        return Vec::new();
+    };
+    let mut disks = Vec::new();
+    for scheme in schemes.filter_map(|entry| entry.ok()) {
+        let is_disk = scheme.file_name().to_str().is_some_and(|name| {
+            // Skip live disks
+            name.starts_with("disk") && name != "disk.live"
+        });
+        if !is_disk {
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(scheme.path()) else {
+            continue;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            // Skip partitions
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| !name.contains('p'))
+            {
+                disks.push(entry.path());
+            }
+        }
+    }
+    disks.sort();
+    disks
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "redox")))]
+fn system_disks() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Finds the single partition matching a selector on the given disks,
+/// or on every disk of the system if none are given
+pub fn find_gpt_partition(
+    selector: &PartitionSelector,
+    disks: &[PathBuf],
+    image_block_size: Option<u32>,
+) -> Result<(PathBuf, gpt::partition::Partition)> {
+    let disks = if disks.is_empty() {
+        system_disks()
+    } else {
+        disks.to_vec()
+    };
+
+    let mut found = Vec::new();
+    for disk in disks {
+        // Disks without a readable GPT cannot contain the partition
+        let Ok(partitions) = gpt_partitions(&disk, image_block_size) else {
+            continue;
+        };
+        for partition in partitions.into_values() {
+            if selector.matches(&partition) {
+                found.push((disk.clone(), partition));
+            }
+        }
+    }
+
+    match found.len() {
+        0 => bail!("no partition matches {selector}"),
+        1 => Ok(found.remove(0)),
+        _ => {
+            let found: Vec<String> = found
+                .iter()
+                .map(|(disk, partition)| format!("{} ({})", disk.display(), partition.part_guid))
+                .collect();
+            bail!(
+                "{selector} matches several partitions, use gpt-guid instead: {}",
+                found.join(", ")
+            );
+        }
+    }
+}